@@ -0,0 +1,111 @@
+use std::{fmt, time::Duration};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    HttpRequest,
+};
+use tracing::info;
+
+use crate::{auth::Consumer, cache::Lookup, health::PROBE_PATHS};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Identifies a request across our logs, traces and responses
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Take the request id sent by the caller, or generate a new one if they didn't send a usable one
+    pub fn from_request(req: &ServiceRequest) -> Self {
+        req.headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| {
+                !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+            })
+            .map(|id| RequestId(id.to_owned()))
+            .unwrap_or_else(|| RequestId(uuid::Uuid::new_v4().to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The translations used while handling a request
+#[derive(Debug)]
+struct TranslationEngines(Vec<String>);
+
+/// Note the translation engine used for the request, to be included in its access log
+pub fn record_engine(req: &HttpRequest, engine: &str) {
+    let mut extensions = req.extensions_mut();
+    match extensions.get_mut::<TranslationEngines>() {
+        Some(TranslationEngines(engines)) => {
+            if !engines.iter().any(|e| e == engine) {
+                engines.push(engine.to_owned());
+            }
+        }
+        None => {
+            extensions.insert(TranslationEngines(vec![engine.to_owned()]));
+        }
+    }
+}
+
+/// How the upstream responses used for a request were found in the caches
+#[derive(Debug)]
+struct CacheLookups(Vec<Lookup>);
+
+/// Note how a cached upstream response was found, to be included in the request's access log
+pub fn record_cache(req: &HttpRequest, lookup: Lookup) {
+    let mut extensions = req.extensions_mut();
+    match extensions.get_mut::<CacheLookups>() {
+        Some(CacheLookups(lookups)) => {
+            if !lookups.contains(&lookup) {
+                lookups.push(lookup);
+            }
+        }
+        None => {
+            extensions.insert(CacheLookups(vec![lookup]));
+        }
+    }
+}
+
+/// Write the access log line for a handled request. Health probes aren't logged
+pub fn log<B>(res: &ServiceResponse<B>, elapsed: Duration) {
+    let req = res.request();
+    if PROBE_PATHS.contains(&req.path()) {
+        return;
+    }
+
+    let extensions = req.extensions();
+    let request_id = extensions.get::<RequestId>().map_or("", RequestId::as_str);
+    let consumer = extensions
+        .get::<Consumer>()
+        .map_or("", |consumer| consumer.0.as_str());
+    let engine = extensions
+        .get::<TranslationEngines>()
+        .map(|engines| engines.0.join(","));
+    let cache = extensions.get::<CacheLookups>().map(|CacheLookups(lookups)| {
+        let lookups: Vec<_> = lookups.iter().map(|lookup| lookup.as_str()).collect();
+        lookups.join(",")
+    });
+
+    info!(
+        target: "access_log",
+        request_id,
+        consumer,
+        method = %req.method(),
+        path = req.path(),
+        status = res.status().as_u16(),
+        latency_ms = elapsed.as_millis() as u64,
+        pokemon = req.match_info().get("pokemon_name").unwrap_or_default(),
+        engine = engine.as_deref().unwrap_or_default(),
+        cache = cache.as_deref().unwrap_or_default(),
+    );
+}
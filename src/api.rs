@@ -1,7 +1,28 @@
-use crate::{pokemon, translations::translate};
-use actix_web::{error::ErrorInternalServerError, get, web, HttpRequest, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+};
+
+use crate::{
+    access_log,
+    auth::Scope,
+    http_cache::{Cached, Lifetime},
+    metrics, pokemon, random,
+    ratelimit::RateLimiter,
+    timeouts,
+    translations::{self, translate},
+    AppConfig,
+};
+use actix_web::{
+    error::{
+        ErrorInternalServerError, ErrorPayloadTooLarge, ErrorServiceUnavailable, InternalError,
+    },
+    get, post, web, HttpRequest, Result,
+};
+use futures::{stream, StreamExt};
 use reqwest_middleware::ClientWithMiddleware;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tracing::warn;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -10,6 +31,7 @@ pub struct PokemonInfo {
     pub name: String,
     pub description: String,
     pub is_legendary: bool,
+    /// Empty if pokeapi doesn't know the habitat
     pub habitat: String,
 }
 
@@ -35,13 +57,12 @@ impl From<pokemon::Species> for PokemonInfo {
         Self {
             name: ps.name,
             is_legendary: ps.is_legendary,
-            habitat: ps.habitat.name,
+            habitat: ps.habitat.map(|habitat| habitat.name).unwrap_or_default(),
             // get first english flavour text
             description: ps
                 .flavor_text_entries
                 .into_iter()
-                .filter(|flavor| flavor.language.name == "en")
-                .next()
+                .find(|flavor| flavor.language.name == "en")
                 .map(|flavor| clean_description(&flavor.flavor_text))
                 .unwrap_or_default(),
         }
@@ -51,11 +72,15 @@ impl From<pokemon::Species> for PokemonInfo {
 #[get("/pokemon/{pokemon_name}")]
 pub async fn get_pokemon(
     client: web::Data<ClientWithMiddleware>,
+    config: web::Data<AppConfig>,
     req: HttpRequest,
     pokemon_name: web::Path<String>,
-) -> Result<Option<web::Json<PokemonInfo>>> {
+) -> Result<Option<Cached<PokemonInfo>>> {
     match pokemon::get_species(&client, &req, &pokemon_name).await {
-        Ok(Some(species)) => Ok(Some(web::Json(species.into()))),
+        Ok(Some(species)) => {
+            let lifetime = Lifetime::MaxAge(config.cache_max_age);
+            Ok(Some(Cached::new(species.into(), lifetime)))
+        }
         Ok(None) => Ok(None),
         Err(err) => Err(ErrorInternalServerError(err)),
     }
@@ -64,25 +89,522 @@ pub async fn get_pokemon(
 #[get("/pokemon/translated/{pokemon_name}")]
 pub async fn get_pokemon_translated(
     client: web::Data<ClientWithMiddleware>,
+    config: web::Data<AppConfig>,
     req: HttpRequest,
     pokemon_name: web::Path<String>,
-) -> Result<Option<web::Json<PokemonInfo>>> {
+) -> Result<Option<Cached<PokemonInfo>>> {
     match pokemon::get_species(&client, &req, &pokemon_name).await {
         Ok(Some(species)) => {
             let mut info: PokemonInfo = species.into();
+            let lifetime = if translate_info(&client, &req, &mut info).await {
+                Lifetime::MaxAge(config.translated_cache_max_age)
+            } else {
+                Lifetime::NoStore
+            };
+            Ok(Some(Cached::new(info, lifetime)))
+        }
+        Ok(None) => Ok(None),
+        Err(err) => Err(ErrorInternalServerError(err)),
+    }
+}
+
+#[get("/pokemon/random")]
+pub async fn get_random_pokemon(
+    client: web::Data<ClientWithMiddleware>,
+    req: HttpRequest,
+    filters: web::Query<random::Filters>,
+) -> Result<Option<Cached<PokemonInfo>>> {
+    let mut rng = rand::thread_rng();
+    pick_pokemon(&client, &req, &filters, &mut rng, false, Lifetime::NoStore).await
+}
+
+#[get("/pokemon/translated/random")]
+pub async fn get_random_pokemon_translated(
+    client: web::Data<ClientWithMiddleware>,
+    req: HttpRequest,
+    filters: web::Query<random::Filters>,
+) -> Result<Option<Cached<PokemonInfo>>> {
+    let mut rng = rand::thread_rng();
+    pick_pokemon(&client, &req, &filters, &mut rng, true, Lifetime::NoStore).await
+}
 
-            match translate(&client, &req, info.translation(), &info.description).await {
-                Ok(desc) => info.description = desc,
-                Err(err) => warn!(%err, "error getting translation"),
+#[get("/pokemon/daily")]
+pub async fn get_daily_pokemon(
+    client: web::Data<ClientWithMiddleware>,
+    config: web::Data<AppConfig>,
+    req: HttpRequest,
+    filters: web::Query<random::Filters>,
+) -> Result<Option<Cached<PokemonInfo>>> {
+    let mut rng = random::daily_rng(&config.daily_seed);
+    let lifetime = Lifetime::MaxAge(config.cache_max_age.min(random::secs_until_next_day()));
+    pick_pokemon(&client, &req, &filters, &mut rng, false, lifetime).await
+}
+
+#[get("/pokemon/translated/daily")]
+pub async fn get_daily_pokemon_translated(
+    client: web::Data<ClientWithMiddleware>,
+    config: web::Data<AppConfig>,
+    req: HttpRequest,
+    filters: web::Query<random::Filters>,
+) -> Result<Option<Cached<PokemonInfo>>> {
+    let mut rng = random::daily_rng(&config.daily_seed);
+    let max_age = config.translated_cache_max_age;
+    let lifetime = Lifetime::MaxAge(max_age.min(random::secs_until_next_day()));
+    pick_pokemon(&client, &req, &filters, &mut rng, true, lifetime).await
+}
+
+/// Pick a pokemon, which can be cached for `lifetime`.
+/// If it should be translated but that fails, it can't be cached at all.
+/// If the search gave up before finding one, it's unavailable rather than not found,
+/// as trying again may find one
+async fn pick_pokemon(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    filters: &random::Filters,
+    rng: &mut impl rand::Rng,
+    translated: bool,
+    lifetime: Lifetime,
+) -> Result<Option<Cached<PokemonInfo>>> {
+    match random::pick_species(client, req, filters, rng).await {
+        Ok(Some(species)) => {
+            let mut info: PokemonInfo = species.into();
+            let lifetime = if translated && !translate_info(client, req, &mut info).await {
+                Lifetime::NoStore
+            } else {
+                lifetime
             };
+            Ok(Some(Cached::new(info, lifetime)))
+        }
+        Ok(None) => Ok(None),
+        Err(err) if err.is::<random::GaveUp>() => Err(ErrorServiceUnavailable(err)),
+        Err(err) => Err(ErrorInternalServerError(err)),
+    }
+}
 
-            Ok(Some(web::Json(info)))
+/// Translate the description of the pokemon according to [`PokemonInfo::translation`].
+/// If the translation fails or the request's deadline passes, the original description is kept
+/// and `false` is returned
+async fn translate_info(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    info: &mut PokemonInfo,
+) -> bool {
+    access_log::record_engine(req, info.translation());
+    let translation = translate(client, req, info.translation(), &info.description);
+    match timeouts::before_deadline(req, translation).await {
+        Ok(desc) => {
+            info.description = desc;
+            true
+        }
+        Err(err) => {
+            warn!(%err, "error getting translation");
+            metrics::record_translation_fallback(&*err);
+            false
+        }
+    }
+}
+
+/// The translated description of the species, along with its name
+async fn translated_description(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    species: pokemon::Species,
+) -> (String, String) {
+    let mut info: PokemonInfo = species.into();
+    translate_info(client, req, &mut info).await;
+    (info.name, info.description)
+}
+
+/// The most pokemon translated for one request, as funtranslations has a small shared quota.
+/// Any others are left without a description
+const MAX_TRANSLATIONS: usize = 20;
+
+/// How many pokemon are looked up and translated at once for one request
+const TRANSLATION_CONCURRENCY: usize = 4;
+
+/// Charge the client for making `translations` translations, which the request itself counted as one.
+/// Fails with a 429 if that takes them over their limit
+fn charge_translations(
+    rate_limiter: &RateLimiter,
+    req: &HttpRequest,
+    translations: usize,
+) -> Result<()> {
+    let extra = u32::try_from(translations.saturating_sub(1)).unwrap_or(u32::MAX);
+    match rate_limiter.charge(req, Scope::Translated, extra) {
+        Some(quota) if quota.exceeded() => Err(InternalError::from_response(
+            "rate limit exceeded",
+            quota.too_many_requests(),
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Look up the translated description of each of the named pokemon,
+/// [`TRANSLATION_CONCURRENCY`] at a time, keyed by name. Any pokemon that can't be looked up is left out
+async fn translated_descriptions<'a>(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    names: impl IntoIterator<Item = &'a str>,
+) -> HashMap<String, String> {
+    let lookups = names.into_iter().map(|name| async move {
+        match pokemon::get_species(client, req, name).await {
+            Ok(Some(species)) => Some(translated_description(client, req, species).await),
+            Ok(None) => None,
+            Err(err) => {
+                warn!(%err, name, "error getting species");
+                None
+            }
+        }
+    });
+
+    stream::iter(lookups)
+        .buffer_unordered(TRANSLATION_CONCURRENCY)
+        .filter_map(|description| async move { description })
+        .collect()
+        .await
+}
+
+/// A pokemon in an evolution chain, along with everything it can evolve into
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Evolution {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The ways this pokemon can be evolved into. Empty for the base of the chain
+    pub triggers: Vec<EvolutionTrigger>,
+    pub evolves_to: Vec<Evolution>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EvolutionTrigger {
+    pub trigger: String,
+    /// Any conditions that must be met, keyed by the pokeapi evolution detail name
+    pub conditions: BTreeMap<String, Value>,
+}
+
+impl From<serde_json::Map<String, Value>> for EvolutionTrigger {
+    fn from(mut details: serde_json::Map<String, Value>) -> Self {
+        let trigger = details
+            .remove("trigger")
+            .and_then(named_resource)
+            .and_then(|name| name.as_str().map(str::to_owned))
+            .unwrap_or_default();
+
+        // pokeapi lists every possible condition, using null/false/"" for the unused ones
+        let conditions = details
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                Value::Null | Value::Bool(false) => None,
+                Value::String(s) if s.is_empty() => None,
+                value => Some((key, named_resource(value)?)),
+            })
+            .collect();
+
+        Self {
+            trigger,
+            conditions,
+        }
+    }
+}
+
+/// Simplify a pokeapi `{ "name": ..., "url": ... }` resource down to just the name
+fn named_resource(value: Value) -> Option<Value> {
+    match value {
+        Value::Object(mut obj) if obj.contains_key("name") => obj.remove("name"),
+        Value::Null => None,
+        value => Some(value),
+    }
+}
+
+impl Evolution {
+    fn from_chain(link: pokemon::ChainLink, descriptions: &mut HashMap<String, String>) -> Self {
+        Self {
+            description: descriptions.remove(&link.species.name),
+            name: link.species.name,
+            triggers: link.evolution_details.into_iter().map(Into::into).collect(),
+            evolves_to: link
+                .evolves_to
+                .into_iter()
+                .map(|link| Self::from_chain(link, descriptions))
+                .collect(),
+        }
+    }
+}
+
+impl pokemon::ChainLink {
+    fn species_names(&self) -> Vec<&str> {
+        let mut names = vec![self.species.name.as_str()];
+        for link in &self.evolves_to {
+            names.extend(link.species_names());
+        }
+        names
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EvolutionsQuery {
+    /// Include the translated description of every pokemon in the chain
+    #[serde(default)]
+    translated: bool,
+}
+
+#[get("/pokemon/{pokemon_name}/evolutions")]
+pub async fn get_pokemon_evolutions(
+    client: web::Data<ClientWithMiddleware>,
+    rate_limiter: web::Data<RateLimiter>,
+    req: HttpRequest,
+    pokemon_name: web::Path<String>,
+    query: web::Query<EvolutionsQuery>,
+) -> Result<Option<web::Json<Evolution>>> {
+    let species = match pokemon::get_species(&client, &req, &pokemon_name).await {
+        Ok(Some(species)) => species,
+        Ok(None) => return Ok(None),
+        Err(err) => return Err(ErrorInternalServerError(err)),
+    };
+
+    let chain = match species.evolution_chain_id() {
+        Some(chain_id) => {
+            pokemon::get_evolution_chain(&client, &req, chain_id)
+                .await
+                .map_err(ErrorInternalServerError)?
+                .chain
+        }
+        // a species without a chain doesn't evolve
+        None => pokemon::ChainLink {
+            species: pokemon::NamedResource {
+                name: species.name.clone(),
+            },
+            evolution_details: vec![],
+            evolves_to: vec![],
+        },
+    };
+
+    let mut descriptions = if query.translated {
+        // the requested species was already fetched, so only the rest of the chain is looked up
+        let others: Vec<&str> = chain
+            .species_names()
+            .into_iter()
+            .filter(|name| *name != species.name)
+            .take(MAX_TRANSLATIONS - 1)
+            .collect();
+        charge_translations(&rate_limiter, &req, others.len() + 1)?;
+        let (mut descriptions, (name, description)) = futures::join!(
+            translated_descriptions(&client, &req, others),
+            translated_description(&client, &req, species),
+        );
+        descriptions.insert(name, description);
+        descriptions
+    } else {
+        HashMap::new()
+    };
+
+    let evolution = Evolution::from_chain(chain, &mut descriptions);
+    Ok(Some(web::Json(evolution)))
+}
+
+/// Battle data for a pokemon
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PokemonStats {
+    pub name: String,
+    /// Type names, in slot order
+    pub types: Vec<String>,
+    pub abilities: Vec<Ability>,
+    /// Base stat values, keyed by stat name
+    pub base_stats: BTreeMap<String, u32>,
+    /// Height in decimetres
+    pub height: u32,
+    /// Weight in hectograms
+    pub weight: u32,
+    pub sprite: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Ability {
+    pub name: String,
+    pub is_hidden: bool,
+}
+
+impl From<pokemon::Pokemon> for PokemonStats {
+    fn from(mut p: pokemon::Pokemon) -> Self {
+        p.types.sort_by_key(|t| t.slot);
+        p.abilities.sort_by_key(|a| a.slot);
+
+        Self {
+            name: p.name,
+            types: p.types.into_iter().map(|t| t.type_.name).collect(),
+            abilities: p
+                .abilities
+                .into_iter()
+                .map(|a| Ability {
+                    name: a.ability.name,
+                    is_hidden: a.is_hidden,
+                })
+                .collect(),
+            base_stats: p
+                .stats
+                .into_iter()
+                .map(|s| (s.stat.name, s.base_stat))
+                .collect(),
+            height: p.height,
+            weight: p.weight,
+            sprite: p.sprites.front_default,
+        }
+    }
+}
+
+#[get("/pokemon/{pokemon_name}/stats")]
+pub async fn get_pokemon_stats(
+    client: web::Data<ClientWithMiddleware>,
+    config: web::Data<AppConfig>,
+    req: HttpRequest,
+    pokemon_name: web::Path<String>,
+) -> Result<Option<Cached<PokemonStats>>> {
+    match pokemon::get_pokemon(&client, &req, &pokemon_name).await {
+        Ok(Some(pokemon)) => {
+            let lifetime = Lifetime::MaxAge(config.cache_max_age);
+            Ok(Some(Cached::new(pokemon.into(), lifetime)))
         }
         Ok(None) => Ok(None),
         Err(err) => Err(ErrorInternalServerError(err)),
     }
 }
 
+/// A habitat and the species that can be found there
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HabitatInfo {
+    pub name: String,
+    pub species: Vec<HabitatSpecies>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HabitatSpecies {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[get("/habitats")]
+pub async fn get_habitats(
+    client: web::Data<ClientWithMiddleware>,
+    req: HttpRequest,
+) -> Result<web::Json<Vec<String>>> {
+    match pokemon::get_habitats(&client, &req).await {
+        Ok(habitats) => Ok(web::Json(habitats.into_iter().map(|h| h.name).collect())),
+        Err(err) => Err(ErrorInternalServerError(err)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HabitatQuery {
+    /// Include the translated description of every species in the habitat
+    #[serde(default)]
+    translated: bool,
+}
+
+#[get("/habitats/{habitat_name}")]
+pub async fn get_habitat(
+    client: web::Data<ClientWithMiddleware>,
+    rate_limiter: web::Data<RateLimiter>,
+    req: HttpRequest,
+    habitat_name: web::Path<String>,
+    query: web::Query<HabitatQuery>,
+) -> Result<Option<web::Json<HabitatInfo>>> {
+    let habitat = match pokemon::get_habitat(&client, &req, &habitat_name).await {
+        Ok(Some(habitat)) => habitat,
+        Ok(None) => return Ok(None),
+        Err(err) => return Err(ErrorInternalServerError(err)),
+    };
+
+    let mut descriptions = if query.translated {
+        let names: Vec<&str> = habitat
+            .pokemon_species
+            .iter()
+            .map(|s| s.name.as_str())
+            .take(MAX_TRANSLATIONS)
+            .collect();
+        charge_translations(&rate_limiter, &req, names.len())?;
+        translated_descriptions(&client, &req, names).await
+    } else {
+        HashMap::new()
+    };
+
+    Ok(Some(web::Json(HabitatInfo {
+        name: habitat.name,
+        species: habitat
+            .pokemon_species
+            .into_iter()
+            .map(|s| HabitatSpecies {
+                description: descriptions.remove(&s.name),
+                name: s.name,
+            })
+            .collect(),
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TranslateRequest {
+    pub text: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TranslationInfo {
+    /// The translated text, or the original text if it couldn't be translated
+    pub text: String,
+    pub translated: bool,
+    pub original: String,
+    pub engine: String,
+}
+
+#[post("/translate/{translation}")]
+pub async fn post_translate(
+    client: web::Data<ClientWithMiddleware>,
+    config: web::Data<AppConfig>,
+    req: HttpRequest,
+    translation: web::Path<String>,
+    body: web::Json<TranslateRequest>,
+) -> Result<Option<web::Json<TranslationInfo>>> {
+    // only engines the service supports are passed on to funtranslations
+    let translation = translation.into_inner();
+    if !translations::ENGINES.contains(&translation.as_str()) {
+        return Ok(None);
+    }
+
+    let TranslateRequest { text } = body.into_inner();
+
+    if text.chars().count() > config.max_translation_length {
+        return Err(ErrorPayloadTooLarge(format!(
+            "text must be at most {} characters",
+            config.max_translation_length
+        )));
+    }
+
+    access_log::record_engine(&req, &translation);
+    let translated = translate(&client, &req, &translation, &text);
+    let translated = match timeouts::before_deadline(&req, translated).await {
+        Ok(translated) => Some(translated),
+        Err(err) => {
+            warn!(%err, "error getting translation");
+            metrics::record_translation_fallback(&*err);
+            None
+        }
+    };
+
+    Ok(Some(web::Json(TranslationInfo {
+        translated: translated.is_some(),
+        text: translated.unwrap_or_else(|| text.clone()),
+        original: text,
+        engine: translation,
+    })))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::api::PokemonInfo;
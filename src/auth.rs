@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::Path,
+};
+
+use actix_web::{
+    dev::ServiceRequest,
+    http::header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    web, HttpMessage, HttpResponse,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{health::PROBE_PATHS, reload::Live};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// What an API key may be used for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Untranslated pokemon information
+    Pokemon,
+    /// Anything that goes through funtranslations
+    Translated,
+}
+
+impl Scope {
+    /// The scope needed to make a request to `path` with the query string `query`
+    pub fn of(path: &str, query: &str) -> Self {
+        let translated = path.starts_with("/translate/")
+            || path.split('/').any(|segment| segment == "translated")
+            // decoded as the handlers will, so `%74rue` is still translated.
+            // A query they would reject is treated as translated to be safe
+            || web::Query::<ScopeQuery>::from_query(query)
+                .map_or(true, |query| query.translated);
+
+        if translated {
+            Scope::Translated
+        } else {
+            Scope::Pokemon
+        }
+    }
+}
+
+/// The query parameters that decide the scope of a request
+#[derive(Deserialize)]
+struct ScopeQuery {
+    #[serde(default)]
+    translated: bool,
+}
+
+/// An entry in the API keys file
+#[derive(Debug, Deserialize)]
+struct KeyEntry {
+    /// Hex encoded SHA-256 hash of the key
+    sha256: String,
+    label: String,
+    /// Left out to allow every scope
+    #[serde(default)]
+    scopes: Option<Vec<Scope>>,
+}
+
+#[derive(Debug)]
+struct ApiKey {
+    label: String,
+    scopes: Option<Vec<Scope>>,
+}
+
+/// The label of the API key a request was made with, for logging
+#[derive(Clone, Debug)]
+pub struct Consumer(pub String);
+
+/// The API keys allowed to use the service, read from the live settings.
+/// If there is no keys file, authentication is disabled and every request is allowed
+#[derive(Clone, Debug, Default)]
+pub struct ApiKeys(Live);
+
+/// The keys in a keys file, by the hash of the key
+#[derive(Debug, Default)]
+pub struct Keys(HashMap<String, ApiKey>);
+
+impl Keys {
+    /// Read the keys from the JSON file at `path`
+    ///
+    /// # Errors:
+    /// If the file can't be read or isn't a valid keys file
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let entries: Vec<KeyEntry> = serde_json::from_slice(&fs::read(path)?)?;
+        Ok(Keys(
+            entries
+                .into_iter()
+                .map(|entry| {
+                    let key = ApiKey {
+                        label: entry.label,
+                        scopes: entry.scopes,
+                    };
+                    (entry.sha256.to_lowercase(), key)
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl From<Live> for ApiKeys {
+    fn from(live: Live) -> Self {
+        ApiKeys(live)
+    }
+}
+
+impl ApiKeys {
+    /// Check the request has a key allowed to make it,
+    /// returning the response to send instead if it doesn't.
+    /// Health probes are always allowed
+    pub fn authorize(&self, req: &ServiceRequest) -> Result<(), HttpResponse> {
+        let current = self.0.get();
+        let Keys(keys) = match &current.api_keys {
+            Some(keys) if !PROBE_PATHS.contains(&req.path()) => keys,
+            _ => return Ok(()),
+        };
+
+        let key = match presented_key(req) {
+            Some(key) => key,
+            None => return Err(unauthorized("missing API key")),
+        };
+
+        let key = match keys.get(&hash(key)) {
+            Some(key) => key,
+            None => return Err(unauthorized("invalid API key")),
+        };
+
+        let scope = Scope::of(req.path(), req.query_string());
+        if let Some(scopes) = &key.scopes {
+            if !scopes.contains(&scope) {
+                return Err(
+                    HttpResponse::Forbidden().body("API key not allowed to use this endpoint")
+                );
+            }
+        }
+
+        req.extensions_mut().insert(Consumer(key.label.clone()));
+        Ok(())
+    }
+}
+
+/// The key sent in either the `Authorization: Bearer` or `X-Api-Key` header
+fn presented_key(req: &ServiceRequest) -> Option<&str> {
+    let headers = req.headers();
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok()?.strip_prefix("Bearer "))
+        .or_else(|| headers.get(API_KEY_HEADER)?.to_str().ok())
+        .map(str::trim)
+}
+
+fn hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+fn unauthorized(reason: &'static str) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((WWW_AUTHENTICATE, "Bearer"))
+        .body(reason)
+}
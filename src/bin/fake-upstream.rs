@@ -0,0 +1,188 @@
+//! A fake of pokeapi and the funtranslations translate endpoint, served from fixtures
+//! like those in `replays/`, for local development.
+//!
+//! Point the service at it with `POKEMON_URL` and `TRANSLATIONS_URL`.
+
+use std::{error::Error, path::PathBuf, time::Duration};
+
+use actix_web::{web, App, HttpServer};
+use structopt::StructOpt;
+use tracing::info;
+
+#[path = "../fake_upstream.rs"]
+mod fake_upstream;
+
+use fake_upstream::{Fake, Quota};
+
+#[derive(StructOpt)]
+struct Config {
+    /// Port to listen on. Not `PORT`, so it can share an environment with the service
+    #[structopt(short, long, env = "FAKE_UPSTREAM_PORT", default_value = "8081")]
+    port: u16,
+
+    /// Directory of pokeapi and translation responses, in the format of `replays/`
+    #[structopt(long, env = "FIXTURES_DIR", default_value = "replays")]
+    fixtures: PathBuf,
+
+    /// Milliseconds to delay every response by
+    #[structopt(long, env = "LATENCY_MS", default_value = "0")]
+    latency_ms: u64,
+
+    /// Milliseconds to delay the body of every successful response by, after sending its headers
+    #[structopt(long, env = "BODY_LATENCY_MS", default_value = "0")]
+    body_latency_ms: u64,
+
+    /// Fraction of requests, from 0 to 1, that fail with a 500
+    #[structopt(long, env = "ERROR_RATE", default_value = "0")]
+    error_rate: f64,
+
+    /// Translations allowed per quota window before responding 429, like funtranslations' free tier
+    #[structopt(long, env = "TRANSLATION_QUOTA")]
+    translation_quota: Option<u32>,
+
+    /// Seconds in each translation quota window
+    #[structopt(long, env = "QUOTA_WINDOW", default_value = "3600")]
+    quota_window: u64,
+
+    /// Species to serve with a null habitat, as pokeapi does for some newer species
+    #[structopt(long, env = "NULL_HABITAT", use_delimiter = true)]
+    null_habitat: Vec<String>,
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    let config = Config::from_args_safe()?;
+    let fake = Fake::load(&config.fixtures)?;
+    info!(
+        resources = fake.fixtures.resources.len(),
+        translations = fake.fixtures.translations.len(),
+        "loaded fixtures"
+    );
+
+    let quota_window = Duration::from_secs(config.quota_window);
+    let fake = web::Data::new(Fake {
+        latency: Duration::from_millis(config.latency_ms),
+        body_latency: Duration::from_millis(config.body_latency_ms),
+        error_rate: config.error_rate,
+        null_habitat: config.null_habitat.into_iter().collect(),
+        quota: config
+            .translation_quota
+            .map(|limit| Quota::new(limit, quota_window)),
+        ..fake
+    });
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(fake.clone())
+            .configure(fake_upstream::configure)
+    })
+    .bind(("0.0.0.0", config.port))?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use actix_web::{http::StatusCode, test, web, App};
+    use serde_json::Value;
+
+    use super::fake_upstream::{configure, Fake, Quota};
+
+    fn fake() -> Fake {
+        Fake {
+            null_habitat: std::iter::once("ditto".to_owned()).collect(),
+            ..Fake::load(Path::new("replays")).unwrap()
+        }
+    }
+
+    async fn get(fake: Fake, uri: &str) -> (StatusCode, Value) {
+        let app = App::new()
+            .app_data(web::Data::new(fake))
+            .configure(configure);
+        let app = test::init_service(app).await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        let status = resp.status();
+        let body = test::read_body(resp).await;
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[actix_rt::test]
+    async fn species_from_fixtures() {
+        let (status, body) = get(fake(), "/api/v2/pokemon-species/mewtwo/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["habitat"]["name"], "rare");
+
+        let (status, body) = get(fake(), "/api/v2/pokemon-species/ditto/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["habitat"].is_null());
+
+        let (status, _) = get(fake(), "/api/v2/pokemon-species/mewthree/").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn resources_from_fixtures() {
+        for (uri, name) in [
+            ("/api/v2/pokemon/mewtwo/", "mewtwo"),
+            ("/api/v2/pokemon/150/", "mewtwo"),
+            ("/api/v2/pokemon-habitat/cave/", "cave"),
+            ("/api/v2/generation/1/", "generation-i"),
+        ] {
+            let (status, body) = get(fake(), uri).await;
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+            assert_eq!(body["name"], name, "{}", uri);
+        }
+
+        let (status, body) = get(fake(), "/api/v2/evolution-chain/77/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["chain"]["species"]["name"], "mewtwo");
+
+        let (status, body) = get(fake(), "/api/v2/pokemon-species/?limit=100000").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["results"][0]["name"], "zubat");
+
+        let (status, body) = get(fake(), "/api/v2/pokemon-habitat/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["results"][0]["name"], "cave");
+
+        let (status, _) = get(fake(), "/api/v2/").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn translations_from_fixtures() {
+        let (status, body) = get(fake(), "/translate/yoda?text=It%20was%20created%20by%20a%20scientist%20after%20years%20of%20horrific%20gene%20splicing%20and%20DNA%20engineering%20experiments.").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["contents"]["translated"], "Created by a scientist after years of horrific gene splicing and dna engineering experiments,  it was.");
+
+        let (status, body) = get(fake(), "/translate/pirate?text=Hello").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["contents"]["translated"], "[pirate] Hello");
+    }
+
+    #[actix_rt::test]
+    async fn faults() {
+        let failing = Fake {
+            error_rate: 1.0,
+            ..fake()
+        };
+        let (status, _) = get(failing, "/api/v2/pokemon-species/mewtwo/").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        let limited = Fake {
+            quota: Some(Quota::new(0, Duration::from_secs(60))),
+            ..fake()
+        };
+        let (status, body) = get(limited, "/translate/yoda?text=Hello").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["error"]["code"], 429);
+    }
+}
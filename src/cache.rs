@@ -0,0 +1,483 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{rt, web, HttpRequest};
+use reqwest::header::{
+    HeaderMap, HeaderValue, AGE, CACHE_CONTROL, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    LAST_MODIFIED,
+};
+use reqwest_middleware::RequestBuilder;
+use tracing::{warn, Instrument, Span};
+
+use crate::{
+    access_log, http_cache, metrics,
+    pokemon::{Generation, Pokemon, PokemonHabitat, ResourceList, Species},
+};
+
+/// Once a cache holds this many entries, expired ones are cleared out to make room
+const MAX_ENTRIES: usize = 10_000;
+
+/// How a cached value was found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lookup {
+    /// Served from the cache without asking upstream
+    Hit,
+    /// Fetched from upstream
+    Miss,
+    /// Upstream confirmed the cached value is still current
+    Revalidated,
+    /// Served past its TTL while it's refreshed in the background
+    Stale,
+    /// Served past its TTL because refreshing it failed
+    StaleIfError,
+}
+
+impl Lookup {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Lookup::Hit => "hit",
+            Lookup::Miss => "miss",
+            Lookup::Revalidated => "revalidated",
+            Lookup::Stale => "stale",
+            Lookup::StaleIfError => "stale_if_error",
+        }
+    }
+}
+
+/// A cached upstream response, along with the validators needed to revalidate it
+#[derive(Clone, Debug)]
+pub struct Entry<T> {
+    pub value: T,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    fetched: Instant,
+    ttl: Duration,
+}
+
+impl<T> Entry<T> {
+    pub fn is_fresh(&self) -> bool {
+        self.age() < self.ttl
+    }
+
+    /// How long ago the value was fetched or last revalidated
+    pub fn age(&self) -> Duration {
+        self.fetched.elapsed()
+    }
+
+    /// Whether the entry is at most `window` past its TTL
+    fn is_within(&self, window: Duration) -> bool {
+        self.age() < self.ttl + window
+    }
+
+    /// How much longer until the entry is more than `window` past its TTL
+    fn remaining_within(&self, window: Duration) -> Duration {
+        (self.ttl + window).saturating_sub(self.age())
+    }
+
+    /// Make the request conditional, so upstream can reply 304 if the value hasn't changed
+    pub fn revalidate(&self, mut request: RequestBuilder) -> RequestBuilder {
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        request
+    }
+}
+
+/// How long past their TTL cached values may still be served
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Staleness {
+    /// Serve expired values while they're refreshed in the background for this long
+    pub while_revalidate: Duration,
+    /// Serve expired values when upstream errors for this long
+    pub if_error: Duration,
+}
+
+/// What fetching a value from upstream found
+#[derive(Debug)]
+pub enum Fetched<T> {
+    /// A new value, with the response headers saying how long it may be cached for
+    Value(T, HeaderMap),
+    /// The cached value is still current
+    NotModified(HeaderMap),
+    /// Upstream has no such value
+    NotFound,
+}
+
+impl<T> Fetched<T> {
+    /// The fetched value, for when there's nothing cached to fall back on
+    pub fn into_value(self) -> Option<T> {
+        match self {
+            Fetched::Value(value, _) => Some(value),
+            Fetched::NotModified(_) | Fetched::NotFound => None,
+        }
+    }
+}
+
+/// Upstream responses cached by key,
+/// for as long as their `Cache-Control` allows or a default TTL if they don't say
+#[derive(Debug)]
+pub struct Cache<T> {
+    /// Used to label metrics
+    name: &'static str,
+    entries: Mutex<HashMap<String, Entry<T>>>,
+    /// Keys being refreshed in the background, so each is only refreshed once at a time
+    refreshing: Mutex<HashSet<String>>,
+    default_ttl: Duration,
+    staleness: Staleness,
+}
+
+impl<T: Clone> Cache<T> {
+    pub fn new(name: &'static str, default_ttl: Duration, staleness: Staleness) -> Self {
+        Cache {
+            name,
+            entries: Default::default(),
+            refreshing: Default::default(),
+            default_ttl,
+            staleness,
+        }
+    }
+
+    /// The entry for `key`, even if it's no longer fresh
+    pub fn get(&self, key: &str) -> Option<Entry<T>> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    /// Cache a value fetched from upstream, unless its headers forbid storing it
+    pub fn insert(&self, key: &str, value: T, headers: &HeaderMap) {
+        let ttl = match self.ttl(headers) {
+            Some(ttl) => ttl,
+            None => return self.remove(key),
+        };
+        let entry = Entry {
+            value,
+            etag: headers.get(ETAG).cloned(),
+            last_modified: headers.get(LAST_MODIFIED).cloned(),
+            fetched: Instant::now(),
+            ttl,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            let window = self.staleness.while_revalidate.max(self.staleness.if_error);
+            entries.retain(|_, entry| entry.is_within(window));
+        }
+        if entries.len() < MAX_ENTRIES || entries.contains_key(key) {
+            entries.insert(key.to_owned(), entry);
+        }
+    }
+
+    /// Upstream confirmed the entry is current with a 304, so it's fresh again
+    pub fn refresh(&self, key: &str, entry: Entry<T>, headers: &HeaderMap) {
+        self.insert(
+            key,
+            entry.value,
+            &merge_validators(entry.etag, entry.last_modified, headers),
+        );
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    /// How long a response may be cached for according to its `Cache-Control`, less its `Age`
+    /// if it was served from a cache upstream. `None` if it mustn't be stored
+    fn ttl(&self, headers: &HeaderMap) -> Option<Duration> {
+        let cache_control = match headers
+            .get(CACHE_CONTROL)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(cache_control) => cache_control,
+            None => return Some(self.default_ttl),
+        };
+
+        let mut ttl = self.default_ttl;
+        for directive in cache_control.split(',').map(str::trim) {
+            let directive = directive.to_ascii_lowercase();
+            if directive == "no-store" {
+                return None;
+            } else if directive == "no-cache" {
+                return Some(Duration::ZERO);
+            } else if let Some(secs) = directive.strip_prefix("max-age=") {
+                ttl = secs.parse().map_or(ttl, Duration::from_secs);
+            }
+        }
+
+        let age = headers
+            .get(AGE)
+            .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+            .map_or(Duration::ZERO, Duration::from_secs);
+        Some(ttl.saturating_sub(age))
+    }
+
+    /// Mark `key` as being refreshed. `false` if it already is
+    fn start_refresh(&self, key: &str) -> bool {
+        self.refreshing.lock().unwrap().insert(key.to_owned())
+    }
+
+    fn finish_refresh(&self, key: &str) {
+        self.refreshing.lock().unwrap().remove(key);
+    }
+
+    fn record(&self, req: &HttpRequest, lookup: Lookup) {
+        metrics::record_cache_lookup(self.name, lookup);
+        access_log::record_cache(req, lookup);
+    }
+}
+
+impl<T: Clone + 'static> Cache<T> {
+    /// The value for `key`, from the cache if it's fresh and otherwise from `fetch`,
+    /// which is given the expired entry to revalidate if there is one.
+    ///
+    /// Expired values are served within the stale-while-revalidate window while `fetch`
+    /// runs in the background, and within the stale-if-error window if `fetch` fails.
+    ///
+    /// # Errors:
+    /// Will return [`Err`] if `fetch` fails and there's no value to fall back on
+    pub async fn get_or_fetch<F, Fut>(
+        self: Arc<Self>,
+        req: &HttpRequest,
+        key: &str,
+        fetch: F,
+    ) -> Result<Option<T>, Box<dyn Error>>
+    where
+        F: FnOnce(Option<Entry<T>>) -> Fut + 'static,
+        Fut: Future<Output = Result<Fetched<T>, Box<dyn Error>>> + 'static,
+    {
+        let cached = self.get(key);
+        if let Some(entry) = &cached {
+            if entry.is_fresh() {
+                self.record(req, Lookup::Hit);
+                return Ok(Some(entry.value.clone()));
+            }
+            // served stale whether or not this request started the refresh,
+            // so requests don't pile up on upstream while it's in flight
+            if entry.is_within(self.staleness.while_revalidate) {
+                self.record(req, Lookup::Stale);
+                let remaining = entry.remaining_within(self.staleness.while_revalidate);
+                http_cache::record_stale(req, entry.age(), remaining, false);
+
+                if self.start_refresh(key) {
+                    let (cache, key, entry) = (self.clone(), key.to_owned(), entry.clone());
+                    rt::spawn(
+                        async move {
+                            if let Err(err) = cache.update(&key, Some(entry), fetch).await {
+                                warn!(%err, cache = cache.name, "error refreshing stale value");
+                            }
+                            cache.finish_refresh(&key);
+                        }
+                        .instrument(Span::current()),
+                    );
+                }
+                return Ok(Some(entry.value.clone()));
+            }
+        }
+
+        match self.update(key, cached.clone(), fetch).await {
+            Ok((value, lookup)) => {
+                self.record(req, lookup);
+                Ok(value)
+            }
+            Err(err) => match cached {
+                Some(entry) if entry.is_within(self.staleness.if_error) => {
+                    warn!(%err, cache = self.name, "error fetching value, serving it stale");
+                    self.record(req, Lookup::StaleIfError);
+                    let remaining = entry.remaining_within(self.staleness.if_error);
+                    http_cache::record_stale(req, entry.age(), remaining, true);
+                    Ok(Some(entry.value))
+                }
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// Fetch the value for `key` from upstream and update the cache with it
+    async fn update<F, Fut>(
+        &self,
+        key: &str,
+        cached: Option<Entry<T>>,
+        fetch: F,
+    ) -> Result<(Option<T>, Lookup), Box<dyn Error>>
+    where
+        F: FnOnce(Option<Entry<T>>) -> Fut,
+        Fut: Future<Output = Result<Fetched<T>, Box<dyn Error>>>,
+    {
+        match (fetch(cached.clone()).await?, cached) {
+            (Fetched::Value(value, headers), _) => {
+                self.insert(key, value.clone(), &headers);
+                Ok((Some(value), Lookup::Miss))
+            }
+            (Fetched::NotModified(headers), Some(entry)) => {
+                let value = entry.value.clone();
+                self.refresh(key, entry, &headers);
+                Ok((Some(value), Lookup::Revalidated))
+            }
+            (Fetched::NotModified(_), None) => Err("304 response for an uncached value".into()),
+            (Fetched::NotFound, _) => {
+                self.remove(key);
+                Ok((None, Lookup::Miss))
+            }
+        }
+    }
+}
+
+/// The value for `key` from the cache `which` picks, fetching it as [`Cache::get_or_fetch`] does.
+/// Values are fetched directly if the app has no caches
+///
+/// # Errors:
+/// Will return [`Err`] if `fetch` fails and there's no value to fall back on
+pub async fn get_or_fetch<T, F, Fut>(
+    req: &HttpRequest,
+    which: impl FnOnce(&Caches) -> &Arc<Cache<T>>,
+    key: &str,
+    fetch: F,
+) -> Result<Option<T>, Box<dyn Error>>
+where
+    T: Clone + 'static,
+    F: FnOnce(Option<Entry<T>>) -> Fut + 'static,
+    Fut: Future<Output = Result<Fetched<T>, Box<dyn Error>>> + 'static,
+{
+    match req.app_data::<web::Data<Caches>>() {
+        Some(caches) => which(caches).clone().get_or_fetch(req, key, fetch).await,
+        None => Ok(fetch(None).await?.into_value()),
+    }
+}
+
+/// 304 responses needn't repeat the validators, so keep the cached ones unless they were sent
+fn merge_validators(
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    headers: &HeaderMap,
+) -> HeaderMap {
+    let mut merged = headers.clone();
+    for (name, value) in [(ETAG, etag), (LAST_MODIFIED, last_modified)] {
+        if let (false, Some(value)) = (merged.contains_key(&name), value) {
+            merged.insert(name, value);
+        }
+    }
+    merged
+}
+
+/// The caches of upstream responses, shared between the server's workers
+#[derive(Clone, Debug)]
+pub struct Caches {
+    pub species: Arc<Cache<Species>>,
+    /// Pokemon battle data, cached like the species
+    pub pokemon: Arc<Cache<Pokemon>>,
+    /// The species to pick random pokemon from, cached like the species
+    pub species_list: Arc<Cache<ResourceList>>,
+    pub habitats: Arc<Cache<PokemonHabitat>>,
+    pub generations: Arc<Cache<Generation>>,
+    /// Translated texts, keyed by translation and text
+    pub translations: Arc<Cache<String>>,
+}
+
+impl Caches {
+    /// `species_ttl` (for everything from pokeapi) and `translation_ttl` are how long values
+    /// are cached for when upstream doesn't say
+    pub fn new(species_ttl: Duration, translation_ttl: Duration, staleness: Staleness) -> Self {
+        Caches {
+            species: Arc::new(Cache::new("species", species_ttl, staleness)),
+            pokemon: Arc::new(Cache::new("pokemon", species_ttl, staleness)),
+            species_list: Arc::new(Cache::new("species_list", species_ttl, staleness)),
+            habitats: Arc::new(Cache::new("habitats", species_ttl, staleness)),
+            generations: Arc::new(Cache::new("generations", species_ttl, staleness)),
+            translations: Arc::new(Cache::new("translations", translation_ttl, staleness)),
+        }
+    }
+}
+
+impl Default for Caches {
+    fn default() -> Self {
+        Caches::new(
+            Duration::from_secs(3600),
+            Duration::from_secs(86400),
+            Staleness::default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use actix_web::test::TestRequest;
+    use futures::future;
+    use reqwest::header::{HeaderMap, HeaderValue, AGE, CACHE_CONTROL};
+
+    use super::{Cache, Fetched, Staleness};
+
+    fn cache() -> Cache<()> {
+        Cache::new("test", Duration::from_secs(60), Staleness::default())
+    }
+
+    fn ttl(cache_control: &str) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_str(cache_control).unwrap());
+        cache().ttl(&headers)
+    }
+
+    #[test]
+    fn ttl_from_cache_control() {
+        assert_eq!(
+            ttl("public, max-age=86400, s-maxage=86400"),
+            Some(Duration::from_secs(86400))
+        );
+        assert_eq!(ttl("public"), Some(Duration::from_secs(60)));
+        assert_eq!(ttl("no-cache"), Some(Duration::ZERO));
+        assert_eq!(ttl("private, no-store"), None);
+        assert_eq!(
+            cache().ttl(&HeaderMap::new()),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn ttl_less_age() {
+        let ttl = |age: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=86400"));
+            headers.insert(AGE, HeaderValue::from_str(age).unwrap());
+            cache().ttl(&headers)
+        };
+        assert_eq!(ttl("3600"), Some(Duration::from_secs(82800)));
+        assert_eq!(ttl("90000"), Some(Duration::ZERO));
+        assert_eq!(ttl("invalid"), Some(Duration::from_secs(86400)));
+    }
+
+    #[actix_rt::test]
+    async fn stale_while_refresh_in_flight() {
+        let staleness = Staleness {
+            while_revalidate: Duration::from_secs(60),
+            if_error: Duration::ZERO,
+        };
+        let cache = Arc::new(Cache::new("test", Duration::ZERO, staleness));
+        cache.insert("key", "stale".to_owned(), &HeaderMap::new());
+        let req = TestRequest::default().to_http_request();
+
+        // the first request starts a refresh that doesn't finish
+        let value = cache
+            .clone()
+            .get_or_fetch(&req, "key", |_| future::pending())
+            .await
+            .unwrap();
+        assert_eq!(value.as_deref(), Some("stale"));
+
+        // later ones are served stale too, rather than fetching it themselves
+        let value = cache
+            .clone()
+            .get_or_fetch(&req, "key", |_| async {
+                Err::<Fetched<String>, _>("fetched while a refresh is in flight".into())
+            })
+            .await
+            .unwrap();
+        assert_eq!(value.as_deref(), Some("stale"));
+    }
+}
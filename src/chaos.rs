@@ -0,0 +1,205 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use actix_web::{get, put, rt, web, HttpResponse};
+use reqwest::{Request, Response, StatusCode};
+use reqwest_middleware::{Middleware, Next};
+use serde::{Deserialize, Serialize};
+use task_local_extensions::Extensions;
+use tracing::warn;
+
+use crate::upstream::Upstream;
+
+/// The faults to inject into the calls to each upstream
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Faults {
+    pub pokeapi: FaultRates,
+    pub funtranslations: FaultRates,
+}
+
+/// How often each fault is injected, as a fraction of calls from 0 to 1.
+/// Latency is added on top of the other faults, which are exclusive so their rates can't add up to more than 1
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FaultRates {
+    pub latency_ms: u64,
+    pub latency_rate: f64,
+    pub reset_rate: f64,
+    pub server_error_rate: f64,
+    pub too_many_requests_rate: f64,
+    pub malformed_json_rate: f64,
+}
+
+/// A fault replacing the upstream's response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Fault {
+    Reset,
+    ServerError,
+    TooManyRequests,
+    MalformedJson,
+}
+
+impl Faults {
+    fn for_upstream(&self, upstream: Option<&Upstream>) -> FaultRates {
+        match upstream {
+            Some(Upstream::Pokeapi) => self.pokeapi,
+            Some(Upstream::Funtranslations) => self.funtranslations,
+            None => FaultRates::default(),
+        }
+    }
+}
+
+impl FaultRates {
+    /// # Errors:
+    /// If any rate isn't between 0 and 1, or the exclusive faults add up to more than 1
+    fn validate(&self) -> Result<(), String> {
+        let exclusive = [
+            self.reset_rate,
+            self.server_error_rate,
+            self.too_many_requests_rate,
+            self.malformed_json_rate,
+        ];
+        if !std::iter::once(self.latency_rate)
+            .chain(exclusive)
+            .all(|rate| (0.0..=1.0).contains(&rate))
+        {
+            return Err("rates must be between 0 and 1".to_owned());
+        }
+        if exclusive.iter().sum::<f64>() > 1.0 {
+            return Err("reset, server error, too many requests and malformed JSON rates must add up to at most 1".to_owned());
+        }
+        Ok(())
+    }
+
+    /// The fault picked by `roll`, a random number from 0 to 1
+    fn pick(&self, roll: f64) -> Option<Fault> {
+        let faults = [
+            (Fault::Reset, self.reset_rate),
+            (Fault::ServerError, self.server_error_rate),
+            (Fault::TooManyRequests, self.too_many_requests_rate),
+            (Fault::MalformedJson, self.malformed_json_rate),
+        ];
+        let mut threshold = 0.0;
+        for (fault, rate) in faults {
+            threshold += rate;
+            if roll < threshold {
+                return Some(fault);
+            }
+        }
+        None
+    }
+}
+
+/// Client middleware injecting the configured faults into upstream calls, to exercise the fallbacks.
+/// Clones share the faults, so the admin endpoints can change them
+#[derive(Clone, Debug, Default)]
+pub struct Chaos(Arc<RwLock<Faults>>);
+
+#[async_trait::async_trait]
+impl Middleware for Chaos {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let rates = self.0.read().unwrap().for_upstream(extensions.get());
+
+        if rates.latency_ms > 0 && rand::random::<f64>() < rates.latency_rate {
+            rt::time::sleep(Duration::from_millis(rates.latency_ms)).await;
+        }
+
+        let fault = match rates.pick(rand::random()) {
+            Some(fault) => fault,
+            None => return next.run(req, extensions).await,
+        };
+        warn!(?fault, url = %req.url(), "injecting fault");
+
+        let (status, body) = match fault {
+            Fault::Reset => {
+                let reset = io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "connection reset (injected fault)",
+                );
+                return Err(reqwest_middleware::Error::middleware(reset));
+            }
+            Fault::ServerError => (StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable"),
+            Fault::TooManyRequests => (StatusCode::TOO_MANY_REQUESTS, "Too Many Requests"),
+            Fault::MalformedJson => (StatusCode::OK, "{\"contents\": {\"transl"),
+        };
+        let resp = http::Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(body)
+            .map_err(reqwest_middleware::Error::middleware)?;
+        Ok(resp.into())
+    }
+}
+
+/// Register the admin endpoints controlling the faults `chaos` injects
+pub fn configure(cfg: &mut web::ServiceConfig, chaos: Chaos) {
+    cfg.app_data(web::Data::new(chaos))
+        .service(get_chaos)
+        .service(put_chaos);
+}
+
+#[get("/chaos")]
+async fn get_chaos(chaos: web::Data<Chaos>) -> HttpResponse {
+    HttpResponse::Ok().json(*chaos.0.read().unwrap())
+}
+
+/// Replace the faults being injected. Upstreams and rates left out are set to 0
+#[put("/chaos")]
+async fn put_chaos(chaos: web::Data<Chaos>, faults: web::Json<Faults>) -> HttpResponse {
+    for rates in [faults.pokeapi, faults.funtranslations] {
+        if let Err(err) = rates.validate() {
+            return HttpResponse::BadRequest().body(err);
+        }
+    }
+
+    warn!(faults = ?*faults, "fault injection changed");
+    *chaos.0.write().unwrap() = *faults;
+    HttpResponse::Ok().json(*faults)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fault, FaultRates};
+
+    #[test]
+    fn pick_fault() {
+        let rates = FaultRates {
+            reset_rate: 0.1,
+            server_error_rate: 0.2,
+            malformed_json_rate: 0.3,
+            ..Default::default()
+        };
+        assert_eq!(rates.pick(0.05), Some(Fault::Reset));
+        assert_eq!(rates.pick(0.25), Some(Fault::ServerError));
+        assert_eq!(rates.pick(0.5), Some(Fault::MalformedJson));
+        assert_eq!(rates.pick(0.7), None);
+        assert_eq!(FaultRates::default().pick(0.0), None);
+    }
+
+    #[test]
+    fn validate_rates() {
+        assert!(FaultRates::default().validate().is_ok());
+        assert!(FaultRates {
+            latency_rate: 1.5,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(FaultRates {
+            reset_rate: 0.6,
+            server_error_rate: 0.6,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use actix_cors::Cors;
+use actix_web::http::{HeaderName, Method};
+
+use crate::reload::{Current, Live};
+
+/// Response headers browsers are allowed to read
+const EXPOSED_HEADERS: &[&str] = &[
+    "x-request-id",
+    "retry-after",
+    "ratelimit-limit",
+    "ratelimit-remaining",
+    "ratelimit-reset",
+];
+
+/// Which browser origins may call the API, and how
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    pub allowed_origins: AllowedOrigins,
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<HeaderName>,
+    /// Seconds browsers may cache preflight responses for
+    pub max_age: Option<usize>,
+}
+
+/// Exact origins like `https://example.com`,
+/// wildcard subdomains like `https://*.example.com`, or `*` for any origin.
+/// Read from the live settings, so they can be changed while running
+#[derive(Clone, Debug, Default)]
+pub struct AllowedOrigins(Live);
+
+impl AllowedOrigins {
+    pub fn is_empty(&self) -> bool {
+        self.0.get().cors_origins.is_empty()
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.0
+            .get()
+            .cors_origins
+            .iter()
+            .any(|allowed| origin_matches(allowed, origin))
+    }
+}
+
+impl From<Live> for AllowedOrigins {
+    fn from(live: Live) -> Self {
+        AllowedOrigins(live)
+    }
+}
+
+impl From<Vec<String>> for AllowedOrigins {
+    fn from(cors_origins: Vec<String>) -> Self {
+        AllowedOrigins(Live::new(Current {
+            cors_origins,
+            ..Current::default()
+        }))
+    }
+}
+
+impl CorsConfig {
+    pub fn middleware(&self) -> Cors {
+        let allowed_origins = self.allowed_origins.clone();
+        Cors::default()
+            .allowed_origin_fn(move |origin, _| {
+                allowed_origins.allows(origin.to_str().unwrap_or_default())
+            })
+            .allowed_methods(self.allowed_methods.clone())
+            .allowed_headers(self.allowed_headers.clone())
+            .expose_headers(EXPOSED_HEADERS.iter().copied())
+            .max_age(self.max_age)
+    }
+}
+
+/// Whether `origin` is allowed by the `allowed` pattern
+fn origin_matches(allowed: &str, origin: &str) -> bool {
+    if allowed == "*" || allowed.eq_ignore_ascii_case(origin) {
+        return true;
+    }
+
+    // `https://*.example.com` allows any subdomain of example.com, but not example.com itself
+    let (scheme, domain) = match allowed.split_once("://*.") {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let host = match origin.split_once("://") {
+        Some((origin_scheme, host)) if origin_scheme.eq_ignore_ascii_case(scheme) => host,
+        _ => return false,
+    };
+
+    let host = host.to_ascii_lowercase();
+    let subdomain = host.strip_suffix(&domain.to_ascii_lowercase());
+    matches!(subdomain, Some(subdomain) if subdomain.len() > 1 && subdomain.ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::origin_matches;
+
+    #[test]
+    fn origins() {
+        assert!(origin_matches("*", "https://example.com"));
+        assert!(origin_matches("https://example.com", "https://example.com"));
+        assert!(!origin_matches("https://example.com", "http://example.com"));
+        assert!(origin_matches(
+            "https://*.example.com",
+            "https://app.example.com"
+        ));
+        assert!(origin_matches(
+            "https://*.example.com",
+            "https://a.b.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://badexample.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "http://app.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://app.example.com.evil.com"
+        ));
+    }
+}
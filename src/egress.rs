@@ -0,0 +1,249 @@
+use std::{error::Error, fmt, fs::File, io::BufReader, path::PathBuf, str::FromStr, sync::Arc};
+
+use reqwest::{ClientBuilder, Proxy, Url};
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+    WebPKIVerifier,
+};
+use sha2::{Digest, Sha256};
+
+/// How requests to the upstream APIs leave the network
+#[derive(Clone, Debug, Default)]
+pub struct EgressConfig {
+    /// Proxy for plain HTTP requests
+    pub http_proxy: Option<Url>,
+    /// Proxy for HTTPS requests, which are tunnelled through it with `CONNECT`
+    pub https_proxy: Option<Url>,
+    /// Hosts that are connected to directly, see [`bypasses_proxy`]
+    pub no_proxy: Vec<String>,
+    /// PEM files of CA certificates to trust along with the built in ones,
+    /// e.g. for a proxy inspecting TLS
+    pub ca_files: Vec<PathBuf>,
+    /// Certificates upstream hosts must present
+    pub pins: Vec<Pin>,
+}
+
+/// The SHA-256 fingerprint of the certificate a host must present,
+/// as `host=fingerprint` with the fingerprint in hex, optionally separated by colons
+/// as `openssl x509 -noout -fingerprint -sha256` prints it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pin {
+    pub host: String,
+    pub sha256: Vec<u8>,
+}
+
+impl FromStr for Pin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, fingerprint) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected host=fingerprint, got {}", s))?;
+        let hex: String = fingerprint.chars().filter(|&c| c != ':').collect();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(format!("{} is not a SHA-256 fingerprint", fingerprint));
+        }
+        let sha256 = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("{} is not a SHA-256 fingerprint", fingerprint))?;
+        Ok(Pin {
+            host: host.to_ascii_lowercase(),
+            sha256,
+        })
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=", self.host)?;
+        self.sha256
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+/// The proxy and TLS settings to build upstream clients with
+#[derive(Clone, Default)]
+pub struct Egress {
+    proxy: Option<Proxy>,
+    /// Set if the built in TLS config needs changing
+    tls: Option<ClientConfig>,
+}
+
+impl Egress {
+    /// # Errors:
+    /// If a CA file can't be read or has no certificates in it
+    pub fn new(config: EgressConfig) -> Result<Self, Box<dyn Error>> {
+        let tls = if config.ca_files.is_empty() && config.pins.is_empty() {
+            None
+        } else {
+            Some(tls_config(&config.ca_files, config.pins)?)
+        };
+
+        let proxy = if config.http_proxy.is_none() && config.https_proxy.is_none() {
+            None
+        } else {
+            let EgressConfig {
+                http_proxy,
+                https_proxy,
+                no_proxy,
+                ..
+            } = config;
+            Some(Proxy::custom(move |url| {
+                if bypasses_proxy(&no_proxy, url.host_str()?) {
+                    return None;
+                }
+                match url.scheme() {
+                    "http" => http_proxy.clone(),
+                    "https" => https_proxy.clone(),
+                    _ => None,
+                }
+            }))
+        };
+
+        Ok(Egress { proxy, tls })
+    }
+
+    /// Configure the client to use the proxy and TLS settings.
+    /// Proxies aren't taken from the environment otherwise, so they're only used as configured
+    pub fn apply(&self, builder: ClientBuilder) -> ClientBuilder {
+        let builder = match &self.proxy {
+            Some(proxy) => builder.proxy(proxy.clone()),
+            None => builder.no_proxy(),
+        };
+        match &self.tls {
+            Some(tls) => builder.use_preconfigured_tls(tls.clone()),
+            None => builder,
+        }
+    }
+}
+
+/// Whether `host` matches a `NO_PROXY` entry: `*` for every host,
+/// or a domain matching itself and its subdomains, with or without a leading `.`
+pub fn bypasses_proxy(no_proxy: &[String], host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    no_proxy.iter().any(|entry| {
+        let entry = entry.trim().trim_start_matches('.').to_ascii_lowercase();
+        entry == "*"
+            || host == entry
+            || matches!(host.strip_suffix(&entry), Some(rest) if rest.ends_with('.'))
+    })
+}
+
+/// TLS config trusting the built in roots and the certificates in `ca_files`,
+/// and checking the pins
+fn tls_config(ca_files: &[PathBuf], pins: Vec<Pin>) -> Result<ClientConfig, Box<dyn Error>> {
+    let mut tls = ClientConfig::new();
+    // the protocols reqwest would offer with its own config
+    tls.set_protocols(&["h2".into(), "http/1.1".into()]);
+    tls.root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    for path in ca_files {
+        let (valid, _) = tls
+            .root_store
+            .add_pem_file(&mut BufReader::new(File::open(path)?))
+            .map_err(|()| format!("invalid CA file {:?}", path))?;
+        if valid == 0 {
+            return Err(format!("no certificates in CA file {:?}", path).into());
+        }
+    }
+    if !pins.is_empty() {
+        tls.dangerous()
+            .set_certificate_verifier(Arc::new(PinningVerifier { pins }));
+    }
+    Ok(tls)
+}
+
+/// Verifies certificates as usual, then checks pinned hosts present one of their pinned certificates
+struct PinningVerifier {
+    pins: Vec<Pin>,
+}
+
+impl PinningVerifier {
+    /// Whether `host` is unpinned, or `cert` is pinned for it
+    fn allows(&self, host: &str, cert: &Certificate) -> bool {
+        let host = host.to_ascii_lowercase();
+        let mut pins = self.pins.iter().filter(|pin| pin.host == host).peekable();
+        if pins.peek().is_none() {
+            return true;
+        }
+        let fingerprint = Sha256::digest(&cert.0);
+        pins.any(|pin| pin.sha256[..] == fingerprint[..])
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let verified = WebPKIVerifier::new().verify_server_cert(
+            roots,
+            presented_certs,
+            dns_name,
+            ocsp_response,
+        )?;
+
+        let host: &str = dns_name.into();
+        match presented_certs.first() {
+            Some(cert) if self.allows(host, cert) => Ok(verified),
+            _ => Err(TLSError::General(format!(
+                "certificate for {} doesn't match its pins",
+                host
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::Certificate;
+    use sha2::{Digest, Sha256};
+
+    use super::{bypasses_proxy, Pin, PinningVerifier};
+
+    #[test]
+    fn no_proxy_matching() {
+        let no_proxy = vec!["localhost".to_owned(), ".internal.example".to_owned()];
+        assert!(bypasses_proxy(&no_proxy, "localhost"));
+        assert!(bypasses_proxy(&no_proxy, "internal.example"));
+        assert!(bypasses_proxy(&no_proxy, "api.internal.example"));
+        assert!(!bypasses_proxy(&no_proxy, "notinternal.example"));
+        assert!(!bypasses_proxy(&no_proxy, "pokeapi.co"));
+        assert!(bypasses_proxy(&["*".to_owned()], "pokeapi.co"));
+    }
+
+    #[test]
+    fn pin_parsing() {
+        let hex = "ab".repeat(32);
+        let pin: Pin = format!("PokeAPI.co={}", hex).parse().unwrap();
+        assert_eq!(pin.host, "pokeapi.co");
+        assert_eq!(pin.sha256, vec![0xab; 32]);
+
+        let colons = vec!["AB"; 32].join(":");
+        assert_eq!(format!("pokeapi.co={}", colons).parse(), Ok(pin.clone()));
+        assert_eq!(pin.to_string(), format!("pokeapi.co={}", hex));
+
+        assert!("pokeapi.co".parse::<Pin>().is_err());
+        assert!("pokeapi.co=abcd".parse::<Pin>().is_err());
+    }
+
+    #[test]
+    fn pinned_certificates() {
+        let cert = Certificate(b"certificate".to_vec());
+        let verifier = PinningVerifier {
+            pins: vec![Pin {
+                host: "pokeapi.co".to_owned(),
+                sha256: Sha256::digest(&cert.0).to_vec(),
+            }],
+        };
+        assert!(verifier.allows("pokeapi.co", &cert));
+        assert!(!verifier.allows("pokeapi.co", &Certificate(b"other".to_vec())));
+        assert!(verifier.allows("api.funtranslations.com", &cert));
+    }
+}
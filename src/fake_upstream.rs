@@ -0,0 +1,267 @@
+//! A fake of pokeapi and the funtranslations translate endpoint, served from fixtures
+//! like those in `replays/`. Run as the `fake-upstream` binary for local development,
+//! and started on its own port by the integration tests.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    error::Error,
+    fs,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{get, rt, web, HttpResponse, HttpResponseBuilder};
+use futures::stream;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::warn;
+
+/// Responses to serve
+#[derive(Default)]
+pub struct Fixtures {
+    /// pokeapi responses by their path under `/api/v2/`, eg `pokemon-species/mewtwo`
+    pub resources: HashMap<String, Value>,
+    /// Translation responses by translation and text
+    pub translations: HashMap<(String, String), Value>,
+}
+
+/// The fixtures, and the faults to inject when serving them
+pub struct Fake {
+    pub fixtures: Fixtures,
+    pub latency: Duration,
+    /// Between sending the headers and the body of successful responses
+    pub body_latency: Duration,
+    pub error_rate: f64,
+    pub null_habitat: HashSet<String>,
+    pub quota: Option<Quota>,
+}
+
+impl Fake {
+    /// Serve the fixtures in `dir`, without any faults
+    ///
+    /// # Errors:
+    /// If the directory or one of its JSON files can't be read
+    pub fn load(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(Fake {
+            fixtures: load_fixtures(dir)?,
+            latency: Duration::ZERO,
+            body_latency: Duration::ZERO,
+            error_rate: 0.0,
+            null_habitat: HashSet::new(),
+            quota: None,
+        })
+    }
+
+    /// Delay the response, then fail it if it's picked to error
+    async fn faults(&self) -> Option<HttpResponse> {
+        if !self.latency.is_zero() {
+            rt::time::sleep(self.latency).await;
+        }
+        if self.error_rate > 0.0 && rand::random::<f64>() < self.error_rate {
+            return Some(HttpResponse::InternalServerError().body("Injected error"));
+        }
+        None
+    }
+
+    /// Respond with `body` as JSON, sent `body_latency` after the headers
+    fn json(&self, mut res: HttpResponseBuilder, body: &impl Serialize) -> HttpResponse {
+        let body = web::Bytes::from(serde_json::to_vec(body).expect("JSON values serialize"));
+        res.content_type("application/json");
+        if self.body_latency.is_zero() {
+            return res.body(body);
+        }
+        let latency = self.body_latency;
+        res.streaming(Box::pin(stream::once(async move {
+            rt::time::sleep(latency).await;
+            Ok::<_, Infallible>(body)
+        })))
+    }
+}
+
+/// Fixed window limit on the number of translations
+pub struct Quota {
+    limit: u32,
+    window: Duration,
+    used: Mutex<(Instant, u32)>,
+}
+
+impl Quota {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Quota {
+            limit,
+            window,
+            used: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Count a translation, returning whether it's within the quota
+    fn take(&self) -> bool {
+        let mut used = self.used.lock().unwrap();
+        if used.0.elapsed() >= self.window {
+            *used = (Instant::now(), 0);
+        }
+        used.1 += 1;
+        used.1 <= self.limit
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_resource).service(get_translation);
+}
+
+/// Read the pokeapi and translation responses in `dir`, telling them apart by their contents.
+/// Other files are skipped
+///
+/// # Errors:
+/// If the directory or one of its JSON files can't be read
+pub fn load_fixtures(dir: &Path) -> Result<Fixtures, Box<dyn Error>> {
+    let mut fixtures = Fixtures::default();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let fixture: Value = match serde_json::from_slice(&fs::read(&path)?) {
+            Ok(fixture) => fixture,
+            Err(err) => {
+                warn!(%err, ?path, "skipping invalid fixture");
+                continue;
+            }
+        };
+
+        if let Some(contents) = fixture.get("contents") {
+            let key = |field| {
+                contents
+                    .get(field)
+                    .and_then(Value::as_str)
+                    .map(str::to_owned)
+            };
+            if let (Some(translation), Some(text)) = (key("translation"), key("text")) {
+                fixtures.translations.insert((translation, text), fixture);
+            }
+        } else {
+            for path in pokeapi_paths(&fixture) {
+                fixtures.resources.insert(path, fixture.clone());
+            }
+        }
+    }
+
+    Ok(fixtures)
+}
+
+/// The paths under `/api/v2/` pokeapi serves the fixture at, worked out from its fields.
+/// Resources are served by both name and id, as pokeapi does
+fn pokeapi_paths(fixture: &Value) -> Vec<String> {
+    let has = |field| fixture.get(field).is_some();
+    let kind = if has("flavor_text_entries") {
+        "pokemon-species"
+    } else if has("stats") && has("sprites") {
+        "pokemon"
+    } else if has("chain") {
+        "evolution-chain"
+    } else if has("main_region") {
+        "generation"
+    } else if has("pokemon_species") {
+        "pokemon-habitat"
+    } else if let Some(results) = fixture.get("results") {
+        // lists are served under the kind of resource they list, taken from their urls
+        let url = results.pointer("/0/url").and_then(Value::as_str);
+        let kind = url.and_then(|url| url.split("/api/v2/").nth(1)?.split('/').next());
+        return kind.map(str::to_owned).into_iter().collect();
+    } else {
+        return Vec::new();
+    };
+
+    ["name", "id"]
+        .iter()
+        .filter_map(|field| match fixture.get(field)? {
+            Value::String(name) => Some(name.clone()),
+            Value::Number(id) => Some(id.to_string()),
+            _ => None,
+        })
+        .map(|key| format!("{}/{}", kind, key))
+        .collect()
+}
+
+#[get("/api/v2/{path:.*}")]
+async fn get_resource(fake: web::Data<Fake>, path: web::Path<String>) -> HttpResponse {
+    if let Some(res) = fake.faults().await {
+        return res;
+    }
+
+    // the root lists the kinds of resource, and is what readiness checks call
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        let kinds: HashSet<_> = fake
+            .fixtures
+            .resources
+            .keys()
+            .filter_map(|path| path.split('/').next())
+            .collect();
+        return fake.json(HttpResponse::Ok(), &kinds);
+    }
+
+    let mut resource = match fake.fixtures.resources.get(path) {
+        Some(resource) => resource.clone(),
+        None => return HttpResponse::NotFound().body("Not Found"),
+    };
+    if let Some(name) = path.strip_prefix("pokemon-species/") {
+        if fake.null_habitat.contains(name) {
+            resource["habitat"] = Value::Null;
+        }
+    }
+    fake.json(HttpResponse::Ok(), &resource)
+}
+
+#[derive(Deserialize)]
+struct TranslationQuery {
+    text: String,
+}
+
+#[get("/translate/{translation}")]
+async fn get_translation(
+    fake: web::Data<Fake>,
+    translation: web::Path<String>,
+    query: web::Query<TranslationQuery>,
+) -> HttpResponse {
+    if let Some(res) = fake.faults().await {
+        return res;
+    }
+
+    if let Some(quota) = &fake.quota {
+        if !quota.take() {
+            return HttpResponse::TooManyRequests().json(json!({
+                "error": {
+                    "code": 429,
+                    "message": format!(
+                        "Too Many Requests: Rate limit of {} requests per {} seconds exceeded.",
+                        quota.limit,
+                        quota.window.as_secs()
+                    ),
+                }
+            }));
+        }
+    }
+
+    let translation = translation.into_inner();
+    let key = (translation, query.into_inner().text);
+    match fake.fixtures.translations.get(&key) {
+        Some(fixture) => fake.json(HttpResponse::Ok(), fixture),
+        // texts without a fixture are still translated, so any species can be looked up
+        None => {
+            let (translation, text) = key;
+            let fixture = json!({
+                "success": { "total": 1 },
+                "contents": {
+                    "translated": format!("[{}] {}", translation, text),
+                    "text": text,
+                    "translation": translation,
+                }
+            });
+            fake.json(HttpResponse::Ok(), &fixture)
+        }
+    }
+}
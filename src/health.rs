@@ -0,0 +1,175 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use reqwest_middleware::ClientWithMiddleware;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{config::Readiness, upstream::Upstream, AppConfig};
+
+/// Paths polled by orchestrators, which are left out of access logs and metrics
+pub const PROBE_PATHS: &[&str] = &["/healthz", "/readyz", "/version"];
+
+/// Set once the server starts shutting down, to fail `/readyz` while in-flight requests drain
+#[derive(Clone, Debug, Default)]
+pub struct Draining(Arc<AtomicBool>);
+
+impl Draining {
+    pub fn start(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The result of the last upstream check, shared between the server's workers
+/// so `/readyz` can report it rather than calling the upstreams for every probe
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamChecks(Arc<Mutex<Option<(Instant, Upstreams)>>>);
+
+/// Whether each upstream API is reachable
+type Upstreams = BTreeMap<&'static str, bool>;
+
+impl UpstreamChecks {
+    /// The last result, if it was checked less than `ttl` ago
+    fn fresh(&self, ttl: Duration) -> Option<Upstreams> {
+        match &*self.0.lock().unwrap() {
+            Some((checked, upstreams)) if checked.elapsed() < ttl => Some(upstreams.clone()),
+            _ => None,
+        }
+    }
+
+    fn set(&self, upstreams: Upstreams) {
+        *self.0.lock().unwrap() = Some((Instant::now(), upstreams));
+    }
+}
+
+#[get("/healthz")]
+pub async fn get_healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadyInfo {
+    pub ready: bool,
+    /// Whether the server is shutting down. Left out unless it is
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub draining: bool,
+    /// Whether each upstream API is reachable. Left out if upstreams aren't checked
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub upstreams: Upstreams,
+}
+
+#[get("/readyz")]
+pub async fn get_readyz(
+    client: web::Data<ClientWithMiddleware>,
+    config: web::Data<AppConfig>,
+    draining: web::Data<Draining>,
+    checks: web::Data<UpstreamChecks>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let draining = draining.is_draining();
+    let upstreams = if config.readiness == Readiness::Off || draining {
+        Upstreams::new()
+    } else if let Some(upstreams) = checks.fresh(config.readiness_ttl) {
+        upstreams
+    } else {
+        let upstreams = check_upstreams(&client, &req).await;
+        checks.set(upstreams.clone());
+        upstreams
+    };
+
+    let ready =
+        !draining && (config.readiness != Readiness::Strict || upstreams.values().all(|&up| up));
+    let info = ReadyInfo {
+        ready,
+        draining,
+        upstreams,
+    };
+
+    if ready {
+        HttpResponse::Ok().json(info)
+    } else {
+        HttpResponse::ServiceUnavailable().json(info)
+    }
+}
+
+async fn check_upstreams(client: &ClientWithMiddleware, req: &HttpRequest) -> Upstreams {
+    let (pokeapi, funtranslations) = futures::join!(
+        reachable(client, req, Upstream::Pokeapi, "pokeapi_root"),
+        reachable(client, req, Upstream::Funtranslations, "translations_root"),
+    );
+    let mut upstreams = Upstreams::new();
+    upstreams.insert(Upstream::Pokeapi.name(), pokeapi);
+    upstreams.insert(Upstream::Funtranslations.name(), funtranslations);
+    upstreams
+}
+
+/// Check that the upstream responds at all, without spending any rate limited calls
+async fn reachable(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    upstream: Upstream,
+    resource: &str,
+) -> bool {
+    let url = match req.url_for_static(resource) {
+        Ok(url) => url,
+        Err(err) => {
+            warn!(%err, upstream = upstream.name(), "invalid upstream url");
+            return false;
+        }
+    };
+
+    match client
+        .get(url)
+        .send_with_extensions(&mut upstream.extensions())
+        .await
+    {
+        Ok(resp) => !resp.status().is_server_error(),
+        Err(err) => {
+            warn!(%err, upstream = upstream.name(), "upstream unreachable");
+            false
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub build_time: &'static str,
+    pub features: Vec<&'static str>,
+    pub upstreams: BTreeMap<&'static str, String>,
+}
+
+#[get("/version")]
+pub async fn get_version(config: web::Data<AppConfig>) -> web::Json<VersionInfo> {
+    let mut upstreams = BTreeMap::new();
+    upstreams.insert(Upstream::Pokeapi.name(), config.pokemon_url.to_string());
+    upstreams.insert(
+        Upstream::Funtranslations.name(),
+        config.translations_url.to_string(),
+    );
+
+    web::Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        build_time: env!("BUILD_TIME"),
+        features: env!("ENABLED_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+        upstreams,
+    })
+}
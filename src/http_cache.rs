@@ -0,0 +1,178 @@
+use std::{convert::TryFrom, time::Duration};
+
+use actix_web::{
+    dev::ServiceResponse,
+    error::ErrorInternalServerError,
+    http::{
+        header::{AGE, CACHE_CONTROL, ETAG, IF_NONE_MATCH, VARY, WARNING},
+        HeaderValue,
+    },
+    HttpRequest, HttpResponse, Responder,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::auth::Consumer;
+
+/// How long clients and CDNs may cache a response for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lifetime {
+    /// Cacheable for this many seconds
+    MaxAge(u32),
+    /// Must not be cached at all, e.g. because it's a fallback that should be retried
+    NoStore,
+}
+
+impl Lifetime {
+    /// Responses only for the API key they were requested with are `private`,
+    /// so shared caches don't serve them to other clients
+    fn cache_control(self, private: bool) -> String {
+        match (self, private) {
+            (Lifetime::MaxAge(secs), false) => format!("public, max-age={}", secs),
+            (Lifetime::MaxAge(secs), true) => format!("private, max-age={}", secs),
+            (Lifetime::NoStore, _) => "no-store".to_owned(),
+        }
+    }
+}
+
+/// A JSON response with a strong ETag computed from its payload and a `Cache-Control` lifetime.
+/// Requests with a matching `If-None-Match` get a 304 without the body
+#[derive(Debug)]
+pub struct Cached<T> {
+    value: T,
+    lifetime: Lifetime,
+}
+
+impl<T> Cached<T> {
+    pub fn new(value: T, lifetime: Lifetime) -> Self {
+        Cached { value, lifetime }
+    }
+}
+
+impl<T: Serialize> Responder for Cached<T> {
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        let body = match serde_json::to_vec(&self.value) {
+            Ok(body) => body,
+            Err(err) => return HttpResponse::from_error(ErrorInternalServerError(err)),
+        };
+
+        let etag = format!("\"{:x}\"", Sha256::digest(&body));
+        // the request made it past authentication with a key, so API keys are required
+        let authenticated = req.extensions().get::<Consumer>().is_some();
+        let cache_control = self.lifetime.cache_control(authenticated);
+
+        let not_modified = none_match(req, &etag);
+        let mut res = if not_modified {
+            HttpResponse::NotModified()
+        } else {
+            HttpResponse::Ok()
+        };
+        res.insert_header((ETAG, etag))
+            .insert_header((CACHE_CONTROL, cache_control));
+        if authenticated {
+            res.insert_header((VARY, "Authorization, X-Api-Key"));
+        }
+
+        if not_modified {
+            return res.finish();
+        }
+        res.content_type("application/json").body(body)
+    }
+}
+
+/// Whether the request's `If-None-Match` matches the etag, meaning the client already has it.
+/// Uses the weak comparison, as RFC 7232 requires for `If-None-Match`
+fn none_match(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get_all(IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// The oldest stale upstream value a response was built from
+#[derive(Clone, Copy, Debug)]
+struct Stale {
+    age: Duration,
+    /// How much longer the stale values may be served for
+    remaining: Duration,
+    revalidation_failed: bool,
+}
+
+/// Note that the response is built from an upstream value `age` old that's past its TTL,
+/// because it's being refreshed or because refreshing it failed,
+/// and that may only be served for `remaining` longer
+pub fn record_stale(
+    req: &HttpRequest,
+    age: Duration,
+    remaining: Duration,
+    revalidation_failed: bool,
+) {
+    let mut extensions = req.extensions_mut();
+    let stale = match extensions.get::<Stale>() {
+        Some(stale) => Stale {
+            age: stale.age.max(age),
+            remaining: stale.remaining.min(remaining),
+            revalidation_failed: stale.revalidation_failed || revalidation_failed,
+        },
+        None => Stale {
+            age,
+            remaining,
+            revalidation_failed,
+        },
+    };
+    extensions.insert(stale);
+}
+
+/// Add `Age` and `Warning` headers if the response was built from stale upstream values
+pub fn insert_stale_headers<B>(res: &mut ServiceResponse<B>) {
+    let stale = match res.request().extensions().get::<Stale>() {
+        Some(stale) => *stale,
+        None => return,
+    };
+
+    let warning = match stale.revalidation_failed {
+        true => "111 - \"Revalidation Failed\"",
+        false => "110 - \"Response is Stale\"",
+    };
+    let headers = res.headers_mut();
+    headers.insert(AGE, HeaderValue::from(stale.age.as_secs()));
+    headers.insert(WARNING, HeaderValue::from_static(warning));
+
+    // clients mustn't keep the response for longer than the values it was built from
+    let cache_control = headers
+        .get(CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| cap_max_age(value, stale.remaining.as_secs()));
+    if let Some(Ok(value)) = cache_control.map(HeaderValue::try_from) {
+        headers.insert(CACHE_CONTROL, value);
+    }
+}
+
+/// Lower the `max-age` directive of a `Cache-Control` header to at most `secs`
+fn cap_max_age(cache_control: &str, secs: u64) -> String {
+    cache_control
+        .split(',')
+        .map(|directive| {
+            let directive = directive.trim();
+            match directive.strip_prefix("max-age=").map(str::parse::<u64>) {
+                Some(Ok(max_age)) => format!("max-age={}", max_age.min(secs)),
+                _ => directive.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cap_max_age;
+
+    #[test]
+    fn cap_max_age_lowers_only_max_age() {
+        assert_eq!(cap_max_age("public, max-age=3600", 60), "public, max-age=60");
+        assert_eq!(cap_max_age("private, max-age=30", 60), "private, max-age=30");
+        assert_eq!(cap_max_age("no-store", 60), "no-store");
+    }
+}
@@ -1,47 +1,333 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use actix_web::{App, Error, HttpServer, Result, dev::{self, ServiceFactory}, web};
+use actix_cors::Cors;
+use actix_web::{
+    dev::{self, Service, ServiceFactory},
+    http::{HeaderName, HeaderValue},
+    middleware::Condition,
+    web, App, Error, HttpMessage, HttpServer, Result,
+};
+use futures::{
+    future::{self, Either},
+    TryFutureExt,
+};
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use reqwest_tracing::TracingMiddleware;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
+mod access_log;
 mod api;
+mod auth;
+mod cache;
+#[cfg(feature = "chaos")]
+mod chaos;
 mod config;
+mod cors;
+mod egress;
+#[cfg(test)]
+mod fake_upstream;
+mod health;
+mod http_cache;
+mod metrics;
+mod mirrors;
 mod pokemon;
+mod random;
+mod ratelimit;
+mod reload;
+mod replay;
+mod shutdown;
+mod telemetry;
+mod timeouts;
+mod tls;
 mod translations;
+mod upstream;
 
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Parse the app config
-    let config = config::parse()?;
+    // Parse the app config, printing why it's invalid as it is rather than debug formatted
+    let config = config::parse().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1)
+    });
+
+    if let Some(config::Command::Config(config::ConfigCommand::Check)) = config.command {
+        if let Some(path) = &config.config {
+            println!("# settings from {:?}, the environment and arguments", path);
+        }
+        print!("{}", config.redacted()?);
+        return Ok(());
+    }
+
+    // Initialise trace export, if a collector is configured
+    let tracer = match &config.otlp_endpoint {
+        Some(endpoint) => Some(telemetry::init_tracer(endpoint)?),
+        None => None,
+    };
 
-    // Initialise stdout logging
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+    // Initialise stdout logging.
+    // The otel layer is typed by the subscriber it's on, so it's built separately for each format
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env());
+    match config.log_format {
+        config::LogFormat::Text => logs
+            .finish()
+            .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+            .init(),
+        config::LogFormat::Json => logs
+            .json()
+            .finish()
+            .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+            .init(),
+    }
 
     // Create a new reqwest client with logging
-    let client = reqwest::Client::builder().build()?;
-    let client = ClientBuilder::new(client).with(TracingMiddleware).build();
-
-    // Create a http server and await the future
-    Ok(
-        HttpServer::new(move || new_service(client.clone(), &APP_CONFIG))
-            .bind(("0.0.0.0", config.port))?
-            .run()
-            .await?,
-    )
+    let egress = egress::Egress::new(config.egress())?;
+    // the settings that can be reloaded, along with the API keys if authentication is enabled
+    let live = reload::Live::new(reload::Current::load(&config)?);
+    let mirrors = config.mirrors();
+    let upstream = UpstreamConfig {
+        replay: config.replay_mode(),
+        timeouts: config.upstream_timeouts(),
+        egress,
+        mirrors: live.clone().into(),
+        #[cfg(feature = "chaos")]
+        chaos: chaos::Chaos::default(),
+    };
+    let client = new_client(upstream.clone())?;
+
+    // requests are made to the first mirror, and failed over from there
+    let primary = |urls: &[String]| {
+        let url = urls.first().map_or("", |url| url.trim_end_matches('/'));
+        url.to_owned().into()
+    };
+    let app_config = AppConfig {
+        pokemon_url: primary(&mirrors.pokeapi),
+        translations_url: primary(&mirrors.funtranslations),
+        daily_seed: config.daily_seed.clone().into(),
+        max_translation_length: config.max_translation_length,
+        cache_max_age: config.cache_max_age,
+        translated_cache_max_age: config.translated_cache_max_age,
+        request_deadline: Duration::from_millis(config.request_deadline_ms),
+        serve_metrics: config.metrics_port.is_none(),
+        readiness: config.readiness,
+        readiness_ttl: Duration::from_secs(config.readiness_ttl),
+        cors: Some(cors::CorsConfig {
+            allowed_origins: live.clone().into(),
+            allowed_methods: config.cors_allowed_methods.clone(),
+            allowed_headers: config.cors_allowed_headers.clone(),
+            max_age: config.cors_max_age,
+        })
+        .filter(|cors| !cors.allowed_origins.is_empty()),
+    };
+
+    let api_keys = auth::ApiKeys::from(live.clone());
+    let rate_limiter = ratelimit::RateLimiter::from(live.clone());
+
+    // Reload the config and API keys on SIGHUP, or when their files change
+    let reloader = Arc::new(reload::Reloader::new(
+        &config,
+        live,
+        app_config.cors.is_some(),
+    )?);
+    actix_web::rt::spawn(reload::reload_on_hangup(reloader.clone()));
+    actix_web::rt::spawn(reload::reload_on_change(reloader));
+
+    let caches = cache::Caches::new(
+        Duration::from_secs(config.species_cache_ttl),
+        Duration::from_secs(config.translation_cache_ttl),
+        cache::Staleness {
+            while_revalidate: Duration::from_secs(config.stale_while_revalidate),
+            if_error: Duration::from_secs(config.stale_if_error),
+        },
+    );
+
+    // Load the TLS certificate, if serving HTTPS. It's reloaded when the files change
+    let tls_config = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let cert = Arc::new(tls::ReloadingCert::load(cert.clone(), key.clone())?);
+            actix_web::rt::spawn(tls::reload_on_change(cert.clone()));
+            Some(tls::server_config(cert, config.tls_client_ca.as_deref())?)
+        }
+        _ => None,
+    };
+
+    // Create a http server. Signals are handled by us, to drain it gracefully
+    let signals = shutdown::Signals::listen()?;
+    let draining = health::Draining::default();
+    let upstream_checks = health::UpstreamChecks::default();
+    let server = HttpServer::new({
+        let draining = draining.clone();
+        move || {
+            new_service(
+                client.clone(),
+                &app_config,
+                draining.clone(),
+                upstream_checks.clone(),
+                api_keys.clone(),
+                rate_limiter.clone(),
+                caches.clone(),
+            )
+        }
+    })
+    .disable_signals()
+    .shutdown_timeout(config.shutdown_grace_period);
+    let server = match tls_config {
+        Some(tls_config) => server.bind_rustls(("0.0.0.0", config.port), tls_config)?,
+        None => server.bind(("0.0.0.0", config.port))?,
+    }
+    .run();
+
+    // Create the server redirecting plain HTTP to HTTPS, if enabled
+    let redirect = match config.http_redirect_port {
+        Some(port) => {
+            let https_port = tls::HttpsPort(config.port);
+            Some(
+                HttpServer::new(move || {
+                    App::new()
+                        .app_data(web::Data::new(https_port))
+                        .default_service(web::to(tls::redirect))
+                })
+                .workers(1)
+                .disable_signals()
+                .shutdown_timeout(config.shutdown_grace_period)
+                .bind(("0.0.0.0", port))?
+                .run(),
+            )
+        }
+        None => None,
+    };
+
+    // Create the admin server, if metrics are served separately
+    let admin = match config.metrics_port {
+        Some(port) => Some(
+            HttpServer::new(move || {
+                App::new()
+                    .service(metrics::get_metrics)
+                    .configure(|cfg| admin(cfg, &upstream))
+            })
+            .workers(1)
+            .disable_signals()
+            .shutdown_timeout(config.shutdown_grace_period)
+            .bind(("0.0.0.0", port))?
+            .run(),
+        ),
+        None => None,
+    };
+
+    let servers: Vec<_> = std::iter::once(server)
+        .chain(admin)
+        .chain(redirect)
+        .collect();
+    actix_web::rt::spawn(shutdown::on_signal(
+        signals,
+        draining,
+        Duration::from_secs(config.shutdown_delay),
+        servers.clone(),
+    ));
+
+    // Await the server, along with the admin and redirect servers if there are any
+    futures::future::try_join_all(servers).await?;
+
+    // Flush any spans that haven't been exported yet
+    opentelemetry::global::shutdown_tracer_provider();
+
+    Ok(())
+}
+
+/// How the client makes requests to the upstream APIs.
+/// The default makes them directly, with the default timeouts
+#[derive(Clone, Default)]
+pub struct UpstreamConfig {
+    /// Record responses to or replay them from a directory, if set
+    pub replay: Option<replay::Mode>,
+    pub timeouts: timeouts::UpstreamTimeouts,
+    /// The proxy and TLS settings
+    pub egress: egress::Egress,
+    pub mirrors: mirrors::Mirrors,
+    /// The faults to inject, controlled through the admin port
+    #[cfg(feature = "chaos")]
+    pub chaos: chaos::Chaos,
+}
+
+/// Create the client for upstream requests, with logging, metrics, and the settings of `upstream`
+///
+/// # Errors:
+/// If the client can't be built, or the replay directory can't be used
+pub fn new_client(
+    upstream: UpstreamConfig,
+) -> Result<ClientWithMiddleware, Box<dyn std::error::Error>> {
+    let UpstreamConfig {
+        replay,
+        timeouts,
+        egress,
+        mirrors,
+        #[cfg(feature = "chaos")]
+        chaos,
+    } = upstream;
+    let client = egress.apply(reqwest::Client::builder()).build()?;
+    let client = ClientBuilder::new(client)
+        .with(TracingMiddleware)
+        .with(metrics::UpstreamMetrics);
+    // injected faults come after the metrics, so they show up in them
+    #[cfg(feature = "chaos")]
+    let client = client.with(chaos);
+
+    // these come last, so the other middleware sees replayed responses as if they were real
+    let client = match replay {
+        Some(replay::Mode::Record(dir)) => client.with(replay::Recorder::new(&dir)?),
+        Some(replay::Mode::Replay(dir)) => client.with(replay::Replayer::new(&dir)?),
+        None => client,
+    };
+
+    // failing over comes after replaying, so recordings are made under the first mirror's url.
+    // The last middleware sends the requests, so each attempt is sent separately
+    Ok(client
+        .with(mirrors)
+        .with(timeouts::SendWithTimeouts::new(&timeouts, &egress)?)
+        .build())
 }
 
 /// Default [`AppConfig`] with the production api endpoints configured
 pub static APP_CONFIG: AppConfig = AppConfig {
     pokemon_url: Cow::Borrowed("https://pokeapi.co"),
     translations_url: Cow::Borrowed("https://api.funtranslations.com"),
+    daily_seed: Cow::Borrowed(""),
+    max_translation_length: 1000,
+    cache_max_age: 3600,
+    translated_cache_max_age: 300,
+    request_deadline: Duration::from_secs(10),
+    serve_metrics: true,
+    readiness: config::Readiness::Off,
+    readiness_ttl: Duration::from_secs(10),
+    cors: None,
 };
 
 #[derive(Clone)]
 pub struct AppConfig {
     pokemon_url: Cow<'static, str>,
     translations_url: Cow<'static, str>,
+    daily_seed: Cow<'static, str>,
+    max_translation_length: usize,
+    /// Seconds clients may cache pokemon information for
+    cache_max_age: u32,
+    /// Seconds clients may cache translated pokemon information for, which are served
+    /// uncacheable if translating fails
+    translated_cache_max_age: u32,
+    /// How long after a request arrives that translating gives up, falling back to the original text
+    request_deadline: Duration,
+    /// Whether `/metrics` is part of the API service, rather than a separate admin port
+    serve_metrics: bool,
+    readiness: config::Readiness,
+    /// How long `/readyz` reports the last upstream check for
+    readiness_ttl: Duration,
+    /// Allows browsers to call the API from other origins. Disabled if `None`
+    cors: Option<cors::CorsConfig>,
 }
 
 /// Create a new actix_web App Service.
@@ -49,6 +335,11 @@ pub struct AppConfig {
 pub fn new_service(
     client: ClientWithMiddleware,
     api_config: &AppConfig,
+    draining: health::Draining,
+    upstream_checks: health::UpstreamChecks,
+    api_keys: auth::ApiKeys,
+    rate_limiter: ratelimit::RateLimiter,
+    caches: cache::Caches,
 ) -> App<
     impl ServiceFactory<
         dev::ServiceRequest,
@@ -59,18 +350,144 @@ pub fn new_service(
     >,
     dev::AnyBody,
 > {
+    let request_deadline = api_config.request_deadline;
+    let limiter = rate_limiter.clone();
     App::new()
+        // runs after authentication, so clients are limited by API key where possible
+        .wrap_fn(move |req, srv| match limiter.check(&req) {
+            Some(quota) if quota.exceeded() => {
+                Either::Right(future::ok(req.into_response(quota.too_many_requests())))
+            }
+            _ => Either::Left(srv.call(req).map_ok(|mut res| {
+                // the handler may have charged the client for more than the one request
+                if let Some(quota) = ratelimit::latest_quota(res.request()) {
+                    quota.insert_headers(res.headers_mut());
+                }
+                res
+            })),
+        })
+        .wrap_fn(move |req, srv| match api_keys.authorize(&req) {
+            Ok(()) => Either::Left(srv.call(req)),
+            Err(res) => Either::Right(future::ok(req.into_response(res))),
+        })
+        // preflight requests are answered before authentication, as browsers don't send credentials
+        .wrap(Condition::new(
+            api_config.cors.is_some(),
+            api_config
+                .cors
+                .as_ref()
+                .map_or_else(Cors::default, cors::CorsConfig::middleware),
+        ))
+        .wrap_fn(|req, srv| {
+            let start = Instant::now();
+            let method = req.method().clone();
+            let res = srv.call(req);
+            async move {
+                let res = res.await?;
+                metrics::record_request(&method, &res, start.elapsed());
+                Ok(res)
+            }
+        })
+        .wrap_fn(move |req, srv| {
+            let start = Instant::now();
+            let request_id = access_log::RequestId::from_request(&req);
+            req.extensions_mut().insert(request_id.clone());
+            req.extensions_mut()
+                .insert(timeouts::Deadline(start + request_deadline));
+            let span = telemetry::request_span(&req, &request_id);
+            let res = srv.call(req).instrument(span.clone());
+            async move {
+                let mut res = res.await?;
+                let route = res.request().match_pattern();
+                let route = route.as_deref().unwrap_or("unmatched");
+                let name = format!("{} {}", res.request().method(), route);
+                span.record("otel.name", &name.as_str());
+                span.record("http.status_code", &res.status().as_u16());
+
+                if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+                    res.headers_mut().insert(
+                        HeaderName::from_static(access_log::REQUEST_ID_HEADER),
+                        value,
+                    );
+                }
+                http_cache::insert_stale_headers(&mut res);
+                span.in_scope(|| access_log::log(&res, start.elapsed()));
+                Ok(res)
+            }
+        })
         .app_data(web::Data::new(client))
+        .app_data(web::Data::new(api_config.clone()))
+        .app_data(web::Data::new(draining))
+        .app_data(web::Data::new(upstream_checks))
+        .app_data(web::Data::new(caches))
+        .app_data(web::Data::new(rate_limiter))
+        .external_resource(
+            "pokeapi_root",
+            api_config.pokemon_url.to_string() + "/api/v2/",
+        )
         .external_resource(
             "pokemon_species",
             api_config.pokemon_url.to_string() + "/api/v2/pokemon-species/{pokemon_name}/",
         )
+        .external_resource(
+            "pokemon_species_list",
+            api_config.pokemon_url.to_string() + "/api/v2/pokemon-species/",
+        )
+        .external_resource(
+            "pokemon",
+            api_config.pokemon_url.to_string() + "/api/v2/pokemon/{pokemon_name}/",
+        )
+        .external_resource(
+            "evolution_chain",
+            api_config.pokemon_url.to_string() + "/api/v2/evolution-chain/{chain_id}/",
+        )
+        .external_resource(
+            "pokemon_habitats",
+            api_config.pokemon_url.to_string() + "/api/v2/pokemon-habitat/",
+        )
+        .external_resource(
+            "pokemon_habitat",
+            api_config.pokemon_url.to_string() + "/api/v2/pokemon-habitat/{habitat_name}/",
+        )
+        .external_resource(
+            "generation",
+            api_config.pokemon_url.to_string() + "/api/v2/generation/{generation}/",
+        )
+        .external_resource(
+            "translations_root",
+            api_config.translations_url.to_string() + "/",
+        )
         .external_resource(
             "translations",
             api_config.translations_url.to_string() + "/translate/{translation}",
         )
+        .service(health::get_healthz)
+        .service(health::get_readyz)
+        .service(health::get_version)
+        // these must come before the `{pokemon_name}` routes they would otherwise match
+        .service(api::get_random_pokemon)
+        .service(api::get_daily_pokemon)
+        .service(api::get_random_pokemon_translated)
+        .service(api::get_daily_pokemon_translated)
         .service(api::get_pokemon)
         .service(api::get_pokemon_translated)
+        .service(api::get_pokemon_evolutions)
+        .service(api::get_pokemon_stats)
+        .service(api::get_habitats)
+        .service(api::get_habitat)
+        .service(api::post_translate)
+        .configure(|cfg| {
+            if api_config.serve_metrics {
+                cfg.service(metrics::get_metrics);
+            }
+        })
+}
+
+/// Endpoints only served on the admin port, controlling the upstream client
+#[cfg_attr(not(feature = "chaos"), allow(unused_variables))]
+fn admin(cfg: &mut web::ServiceConfig, upstream: &UpstreamConfig) {
+    #[cfg(feature = "chaos")]
+    chaos::configure(cfg, upstream.chaos.clone());
 }
 
 #[cfg(test)]
@@ -0,0 +1,160 @@
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    dev::ServiceResponse, error::ErrorInternalServerError, get, http::Method, HttpResponse, Result,
+};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+use reqwest::{Request, Response, StatusCode};
+use reqwest_middleware::{Middleware, Next};
+use task_local_extensions::Extensions;
+
+use crate::{
+    cache::Lookup,
+    health::PROBE_PATHS,
+    timeouts::{DeadlineExceeded, TimedOut},
+    upstream::Upstream,
+};
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "Number of requests handled, by route and response status",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Time taken to handle requests, by route and response status",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref UPSTREAM_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "upstream_requests_total",
+        "Number of requests made to upstream APIs, by response status. \
+         Requests that got no response have the status `error`",
+        &["upstream", "status"]
+    )
+    .unwrap();
+    static ref UPSTREAM_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "upstream_request_duration_seconds",
+        "Time taken for requests made to upstream APIs",
+        &["upstream"]
+    )
+    .unwrap();
+    static ref CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "cache_lookups_total",
+        "Number of lookups in the upstream response caches, by whether they were served from the cache",
+        &["cache", "result"]
+    )
+    .unwrap();
+    static ref TRANSLATION_FALLBACKS: IntCounterVec = register_int_counter_vec!(
+        "translation_fallbacks_total",
+        "Number of times the untranslated description was served, by the reason translating failed",
+        &["reason"]
+    )
+    .unwrap();
+}
+
+/// Record a handled request against its route pattern,
+/// so that path parameters like the pokemon name don't blow up the label cardinality.
+/// Health probes aren't recorded
+pub fn record_request<B>(method: &Method, res: &ServiceResponse<B>, elapsed: Duration) {
+    if PROBE_PATHS.contains(&res.request().path()) {
+        return;
+    }
+
+    let route = res.request().match_pattern();
+    let route = route.as_deref().unwrap_or("unmatched");
+    let status = res.status();
+    let labels = [method.as_str(), route, status.as_str()];
+
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Record a lookup in one of the upstream response caches
+pub fn record_cache_lookup(cache: &str, lookup: Lookup) {
+    CACHE_LOOKUPS
+        .with_label_values(&[cache, lookup.as_str()])
+        .inc();
+}
+
+/// Record that a translation failed and the untranslated text was used instead
+pub fn record_translation_fallback(err: &(dyn std::error::Error + 'static)) {
+    TRANSLATION_FALLBACKS
+        .with_label_values(&[fallback_reason(err)])
+        .inc();
+}
+
+fn fallback_reason(err: &(dyn std::error::Error + 'static)) -> &'static str {
+    if err.is::<DeadlineExceeded>() {
+        return "deadline";
+    }
+    let err = match err.downcast_ref::<reqwest_middleware::Error>() {
+        Some(reqwest_middleware::Error::Reqwest(err)) => Some(err),
+        Some(reqwest_middleware::Error::Middleware(err)) if err.is::<TimedOut>() => {
+            return "timeout"
+        }
+        Some(reqwest_middleware::Error::Middleware(_)) => None,
+        None => err.downcast_ref::<reqwest::Error>(),
+    };
+
+    match err {
+        Some(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => "rate_limited",
+        Some(err) if err.status().is_some() => "upstream_status",
+        Some(err) if err.is_timeout() => "timeout",
+        Some(err) if err.is_decode() => "invalid_response",
+        Some(_) => "connection",
+        None => "other",
+    }
+}
+
+/// Client middleware recording the count and latency of requests to each [`Upstream`]
+pub struct UpstreamMetrics;
+
+#[async_trait::async_trait]
+impl Middleware for UpstreamMetrics {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let upstream = extensions.get::<Upstream>().map_or("unknown", |u| u.name());
+
+        let start = Instant::now();
+        let res = next.run(req, extensions).await;
+
+        let status = match &res {
+            Ok(resp) => resp.status().as_str().to_owned(),
+            Err(_) => "error".to_owned(),
+        };
+        UPSTREAM_REQUESTS
+            .with_label_values(&[upstream, &status])
+            .inc();
+        UPSTREAM_REQUEST_DURATION
+            .with_label_values(&[upstream])
+            .observe(start.elapsed().as_secs_f64());
+
+        res
+    }
+}
+
+#[get("/metrics")]
+pub async fn get_metrics() -> Result<HttpResponse> {
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    encoder
+        .encode(&prometheus::gather(), &mut buf)
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buf))
+}
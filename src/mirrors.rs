@@ -0,0 +1,376 @@
+use std::{
+    io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use reqwest::{Request, Response, StatusCode, Url};
+use reqwest_middleware::{Middleware, Next};
+use serde::Serialize;
+use task_local_extensions::Extensions;
+use tracing::{field, info_span, warn, Instrument, Span};
+
+use crate::{reload::Live, upstream::Upstream};
+
+/// The order mirrors are tried in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Always start with the first healthy mirror, so the others are only backups
+    Priority,
+    /// Start with the next mirror along for each request, spreading the load
+    RoundRobin,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "priority" => Ok(Strategy::Priority),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            _ => Err(format!("unknown mirror strategy {:?}", s)),
+        }
+    }
+}
+
+/// The base urls of each upstream's mirrors, the first of which requests are made to
+#[derive(Clone, Debug)]
+pub struct MirrorsConfig {
+    pub pokeapi: Vec<String>,
+    pub funtranslations: Vec<String>,
+    pub strategy: Strategy,
+    /// How long a mirror that failed is skipped for
+    pub cooldown: Duration,
+}
+
+impl Default for MirrorsConfig {
+    fn default() -> Self {
+        MirrorsConfig {
+            pokeapi: Vec::new(),
+            funtranslations: Vec::new(),
+            strategy: Strategy::Priority,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Client middleware failing requests over between an upstream's mirrors.
+///
+/// Requests are made to the first mirror's url, and are sent to each mirror in turn until one
+/// responds without a connection error, server error or 429. Mirrors that fail are skipped for
+/// the cooldown. Once only mirrors cooling down are left, just the first of them is tried, so
+/// requests fail fast while every mirror is failing. Upstreams with one mirror are passed straight on.
+///
+/// The attempts are made in an `upstream` span, recording the mirror that served the request.
+/// The mirrors are read from the live settings, so they can be changed while running
+#[derive(Clone, Debug, Default)]
+pub struct Mirrors(Live);
+
+/// The mirrors of each upstream
+#[derive(Debug)]
+pub struct Groups {
+    pokeapi: Arc<Group>,
+    funtranslations: Arc<Group>,
+    strategy: Strategy,
+    cooldown: Duration,
+}
+
+/// The mirrors of one upstream
+#[derive(Debug, Default)]
+struct Group {
+    /// The base url requests are made to, which is swapped for each mirror's
+    base: String,
+    urls: Vec<String>,
+    /// When each mirror can be tried again after failing
+    down_until: Mutex<Vec<Option<Instant>>>,
+    /// The mirror to start the next request with, for [`Strategy::RoundRobin`]
+    next: AtomicUsize,
+}
+
+impl From<Live> for Mirrors {
+    fn from(live: Live) -> Self {
+        Mirrors(live)
+    }
+}
+
+impl Groups {
+    pub fn new(config: MirrorsConfig) -> Self {
+        let group = |urls: Vec<String>| {
+            let urls = trim(urls);
+            Arc::new(Group::new(urls.first().cloned().unwrap_or_default(), urls))
+        };
+        Groups {
+            pokeapi: group(config.pokeapi),
+            funtranslations: group(config.funtranslations),
+            strategy: config.strategy,
+            cooldown: config.cooldown,
+        }
+    }
+
+    /// The mirrors of `config`, replacing these. Requests are still made to the first mirrors
+    /// these were created with, and are sent to the new mirrors instead. Upstreams whose mirrors
+    /// haven't changed keep track of which are failing
+    pub fn updated(&self, config: MirrorsConfig) -> Self {
+        let group = |current: &Arc<Group>, urls: Vec<String>| {
+            let urls = trim(urls);
+            if urls == current.urls {
+                current.clone()
+            } else {
+                Arc::new(Group::new(current.base.clone(), urls))
+            }
+        };
+        Groups {
+            pokeapi: group(&self.pokeapi, config.pokeapi),
+            funtranslations: group(&self.funtranslations, config.funtranslations),
+            strategy: config.strategy,
+            cooldown: config.cooldown,
+        }
+    }
+}
+
+impl Default for Groups {
+    fn default() -> Self {
+        Groups::new(MirrorsConfig::default())
+    }
+}
+
+fn trim(urls: Vec<String>) -> Vec<String> {
+    urls.into_iter()
+        .map(|url| url.trim_end_matches('/').to_owned())
+        .collect()
+}
+
+impl Group {
+    fn new(base: String, urls: Vec<String>) -> Self {
+        Group {
+            base,
+            down_until: Mutex::new(vec![None; urls.len()]),
+            urls,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether requests are sent to the base url they're made to, so there's nothing to do
+    fn is_direct(&self) -> bool {
+        self.urls.is_empty() || self.urls == [self.base.as_str()]
+    }
+
+    /// The mirrors to try, in order: those that are up by the strategy,
+    /// then the first of those cooling down
+    fn order(&self, strategy: Strategy) -> Vec<usize> {
+        let start = match strategy {
+            Strategy::Priority => 0,
+            Strategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % self.urls.len(),
+        };
+        let now = Instant::now();
+        let down_until = self.down_until.lock().unwrap();
+        let (up, down): (Vec<_>, Vec<_>) = (0..self.urls.len())
+            .map(|i| (start + i) % self.urls.len())
+            .partition(|&i| !matches!(down_until[i], Some(until) if until > now));
+        up.into_iter().chain(down.into_iter().take(1)).collect()
+    }
+
+    fn mark(&self, mirror: usize, down_until: Option<Instant>) {
+        self.down_until.lock().unwrap()[mirror] = down_until;
+    }
+
+    /// `url` with the base swapped for `mirror`'s
+    fn rewrite(&self, url: &Url, mirror: usize) -> Option<Url> {
+        let path = url.as_str().strip_prefix(self.base.as_str())?;
+        if !(path.is_empty() || path.starts_with('/') || path.starts_with('?')) {
+            return None;
+        }
+        Url::parse(&(self.urls[mirror].clone() + path)).ok()
+    }
+}
+
+/// Whether the mirror failed, so the next one should be tried
+fn failed(res: &reqwest_middleware::Result<Response>) -> bool {
+    match res {
+        Ok(res) => res.status().is_server_error() || res.status() == StatusCode::TOO_MANY_REQUESTS,
+        Err(_) => true,
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Mirrors {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let upstream = match extensions.get::<Upstream>() {
+            Some(upstream) => *upstream,
+            None => return next.run(req, extensions).await,
+        };
+        let (group, strategy, cooldown) = {
+            let current = self.0.get();
+            let groups = &current.mirrors;
+            let group = match upstream {
+                Upstream::Pokeapi => &groups.pokeapi,
+                Upstream::Funtranslations => &groups.funtranslations,
+            };
+            (group.clone(), groups.strategy, groups.cooldown)
+        };
+        if group.is_direct() || group.rewrite(req.url(), 0).is_none() {
+            return next.run(req, extensions).await;
+        }
+
+        let span = info_span!(
+            "upstream",
+            upstream = upstream.name(),
+            mirror = field::Empty
+        );
+        fail_over(req, extensions, next, &group, strategy, cooldown)
+            .instrument(span)
+            .await
+    }
+}
+
+/// Send the request to each of the group's mirrors in turn, until one doesn't fail
+async fn fail_over(
+    req: Request,
+    extensions: &mut Extensions,
+    next: Next<'_>,
+    group: &Group,
+    strategy: Strategy,
+    cooldown: Duration,
+) -> reqwest_middleware::Result<Response> {
+    let order = group.order(strategy);
+    let mut req = Some(req);
+    for (attempt, &mirror) in order.iter().enumerate() {
+        // the last attempt, or one whose body can't be cloned, is made with the request itself
+        let mut mirror_req = match req.as_ref().and_then(Request::try_clone) {
+            Some(mirror_req) if attempt + 1 < order.len() => mirror_req,
+            _ => req
+                .take()
+                .expect("the request is only taken for the last attempt"),
+        };
+        *mirror_req.url_mut() = group.rewrite(mirror_req.url(), mirror).ok_or_else(|| {
+            let message = format!("invalid mirror url {}", group.urls[mirror]);
+            reqwest_middleware::Error::middleware(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                message,
+            ))
+        })?;
+
+        let span = info_span!("mirror", mirror = %group.urls[mirror], attempt);
+        let res = next
+            .clone()
+            .run(mirror_req, extensions)
+            .instrument(span)
+            .await;
+        if !failed(&res) {
+            group.mark(mirror, None);
+            Span::current().record("mirror", &group.urls[mirror].as_str());
+            return res;
+        }
+        group.mark(mirror, Some(Instant::now() + cooldown));
+        if req.is_none() {
+            return res;
+        }
+        warn!(
+            mirror = %group.urls[mirror],
+            ?cooldown,
+            "mirror failed, failing over"
+        );
+    }
+    unreachable!("the last mirror's response is always returned")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use reqwest::Url;
+
+    use super::{trim, Group, Groups, MirrorsConfig, Strategy};
+
+    fn group() -> Group {
+        let urls = trim(vec![
+            "http://primary/".to_owned(),
+            "http://backup/base".to_owned(),
+            "http://third".to_owned(),
+        ]);
+        Group::new(urls[0].clone(), urls)
+    }
+
+    #[test]
+    fn rewrite_urls() {
+        let group = group();
+        let url = Url::parse("http://primary/api/v2/pokemon-species/ditto/?a=b").unwrap();
+        assert_eq!(
+            group.rewrite(&url, 1).unwrap().as_str(),
+            "http://backup/base/api/v2/pokemon-species/ditto/?a=b"
+        );
+        assert_eq!(group.rewrite(&url, 0), Some(url));
+        assert_eq!(
+            group.rewrite(&Url::parse("http://other/").unwrap(), 1),
+            None
+        );
+        assert_eq!(
+            group.rewrite(&Url::parse("http://primary.other/").unwrap(), 1),
+            None
+        );
+    }
+
+    #[test]
+    fn order_mirrors() {
+        let group = group();
+        assert_eq!(group.order(Strategy::Priority), vec![0, 1, 2]);
+        assert_eq!(group.order(Strategy::RoundRobin), vec![0, 1, 2]);
+        assert_eq!(group.order(Strategy::RoundRobin), vec![1, 2, 0]);
+
+        group.mark(0, Some(Instant::now() + Duration::from_secs(60)));
+        assert_eq!(group.order(Strategy::Priority), vec![1, 2, 0]);
+        group.mark(0, Some(Instant::now() - Duration::from_secs(1)));
+        assert_eq!(group.order(Strategy::Priority), vec![0, 1, 2]);
+
+        // only the first mirror cooling down is tried
+        for mirror in 0..3 {
+            group.mark(mirror, Some(Instant::now() + Duration::from_secs(60)));
+        }
+        assert_eq!(group.order(Strategy::Priority), vec![0]);
+        group.mark(2, None);
+        assert_eq!(group.order(Strategy::Priority), vec![2, 0]);
+    }
+
+    #[test]
+    fn update_mirrors() {
+        let mirrors = Groups::new(MirrorsConfig {
+            pokeapi: vec!["http://primary".to_owned(), "http://backup".to_owned()],
+            funtranslations: vec!["http://translations".to_owned()],
+            ..MirrorsConfig::default()
+        });
+
+        let groups = mirrors.updated(MirrorsConfig {
+            pokeapi: vec!["http://replacement/".to_owned()],
+            funtranslations: vec!["http://translations".to_owned()],
+            strategy: Strategy::RoundRobin,
+            ..MirrorsConfig::default()
+        });
+
+        assert_eq!(groups.pokeapi.base, "http://primary");
+        assert_eq!(groups.pokeapi.urls, vec!["http://replacement"]);
+        assert!(!groups.pokeapi.is_direct());
+        assert!(groups.funtranslations.is_direct());
+        assert!(std::sync::Arc::ptr_eq(
+            &groups.funtranslations,
+            &mirrors.funtranslations
+        ));
+        assert_eq!(groups.strategy, Strategy::RoundRobin);
+    }
+
+    #[test]
+    fn parse_strategy() {
+        assert_eq!("priority".parse(), Ok(Strategy::Priority));
+        assert_eq!("round-robin".parse(), Ok(Strategy::RoundRobin));
+        assert!("random".parse::<Strategy>().is_err());
+    }
+}
@@ -1,9 +1,20 @@
 use actix_web::HttpRequest;
 use reqwest::StatusCode;
-use reqwest_middleware::ClientWithMiddleware;
-use serde::Deserialize;
+use reqwest_middleware::{ClientWithMiddleware, RequestBuilder};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{Map, Value};
 
-/// Make a GET request to the pokeapi for the provided pokemon species
+use crate::{
+    cache::{self, Entry, Fetched},
+    upstream::Upstream,
+};
+
+/// Make a GET request to the pokeapi for the provided pokemon species.
+///
+/// Species are cached for as long as pokeapi allows, and revalidated with
+/// `If-None-Match`/`If-Modified-Since` once they expire.
+/// Expired species may be served stale, see [`cache::Cache::get_or_fetch`].
+/// Names are looked up in lowercase, as pokeapi names them
 ///
 /// # Errors:
 /// Will return [`Err`] if the http connection could not be made,
@@ -16,37 +27,278 @@ pub async fn get_species(
     req: &HttpRequest,
     pokemon_name: &str,
 ) -> Result<Option<Species>, Box<dyn std::error::Error>> {
-    let resp = client
-        .get(req.url_for("pokemon_species", &[pokemon_name])?)
-        .send()
+    let pokemon_name = pokemon_name.to_lowercase();
+    let request = client.get(req.url_for("pokemon_species", [&pokemon_name])?);
+    let fetch = move |cached| fetch(request, cached);
+    cache::get_or_fetch(req, |caches| &caches.species, &pokemon_name, fetch).await
+}
+
+/// Send the request to pokeapi, made conditional on the cached entry if there is one
+async fn fetch<T: DeserializeOwned>(
+    mut request: RequestBuilder,
+    cached: Option<Entry<T>>,
+) -> Result<Fetched<T>, Box<dyn std::error::Error>> {
+    if let Some(entry) = &cached {
+        request = entry.revalidate(request);
+    }
+
+    let resp = request
+        .send_with_extensions(&mut Upstream::Pokeapi.extensions())
         .await?;
 
     match resp.status() {
-        StatusCode::NOT_FOUND => Ok(None),
-        _ => Ok(Some(resp.error_for_status()?.json().await?)),
+        StatusCode::NOT_FOUND => Ok(Fetched::NotFound),
+        StatusCode::NOT_MODIFIED => Ok(Fetched::NotModified(resp.headers().clone())),
+        _ => {
+            let resp = resp.error_for_status()?;
+            let headers = resp.headers().clone();
+            Ok(Fetched::Value(resp.json().await?, headers))
+        }
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// Make a GET request to the pokeapi for the evolution chain with the provided id
+///
+/// # Errors:
+/// Will return [`Err`] if the http connection could not be made,
+/// if the API responded with an error status code
+/// or if the response body contained invalid JSON.
+pub async fn get_evolution_chain(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    chain_id: &str,
+) -> Result<EvolutionChain, Box<dyn std::error::Error>> {
+    Ok(client
+        .get(req.url_for("evolution_chain", [chain_id])?)
+        .send_with_extensions(&mut Upstream::Pokeapi.extensions())
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+/// Make a GET request to the pokeapi for the provided pokemon.
+///
+/// Pokemon are cached and looked up the same way as species, see [`get_species`]
+///
+/// # Errors:
+/// Will return [`Err`] if the http connection could not be made,
+/// if the API responded with an error status code
+/// or if the response body contained invalid JSON.
+///
+/// Will return [`Ok(None)`] if the API returned a 404 status code
+pub async fn get_pokemon(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    pokemon_name: &str,
+) -> Result<Option<Pokemon>, Box<dyn std::error::Error>> {
+    let pokemon_name = pokemon_name.to_lowercase();
+    let request = client.get(req.url_for("pokemon", [&pokemon_name])?);
+    let fetch = move |cached| fetch(request, cached);
+    cache::get_or_fetch(req, |caches| &caches.pokemon, &pokemon_name, fetch).await
+}
+
+/// Make a GET request to the pokeapi for the list of all pokemon species.
+///
+/// The list is cached like the species, see [`get_species`]
+///
+/// # Errors:
+/// Will return [`Err`] if the http connection could not be made,
+/// if the API responded with an error status code
+/// or if the response body contained invalid JSON.
+pub async fn get_species_list(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+) -> Result<Vec<NamedResource>, Box<dyn std::error::Error>> {
+    let request = client
+        .get(req.url_for_static("pokemon_species_list")?)
+        // the list is paginated, ask for more than there will ever be
+        .query(&[("limit", "100000")]);
+    let fetch = move |cached| fetch(request, cached);
+    let list: Option<ResourceList> =
+        cache::get_or_fetch(req, |caches| &caches.species_list, "", fetch).await?;
+    Ok(list.ok_or("pokeapi has no species list")?.results)
+}
+
+/// Make a GET request to the pokeapi for the provided generation.
+///
+/// Generations are cached like the species, see [`get_species`]
+///
+/// # Errors:
+/// Will return [`Err`] if the http connection could not be made,
+/// if the API responded with an error status code
+/// or if the response body contained invalid JSON.
+///
+/// Will return [`Ok(None)`] if the API returned a 404 status code
+pub async fn get_generation(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    generation: &str,
+) -> Result<Option<Generation>, Box<dyn std::error::Error>> {
+    let generation = generation.to_lowercase();
+    let request = client.get(req.url_for("generation", [&generation])?);
+    let fetch = move |cached| fetch(request, cached);
+    cache::get_or_fetch(req, |caches| &caches.generations, &generation, fetch).await
+}
+
+/// Make a GET request to the pokeapi for the list of all habitats
+///
+/// # Errors:
+/// Will return [`Err`] if the http connection could not be made,
+/// if the API responded with an error status code
+/// or if the response body contained invalid JSON.
+pub async fn get_habitats(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+) -> Result<Vec<NamedResource>, Box<dyn std::error::Error>> {
+    Ok(client
+        .get(req.url_for_static("pokemon_habitats")?)
+        .send_with_extensions(&mut Upstream::Pokeapi.extensions())
+        .await?
+        .error_for_status()?
+        .json::<ResourceList>()
+        .await?
+        .results)
+}
+
+/// Make a GET request to the pokeapi for the provided habitat.
+///
+/// Habitats are cached like the species, see [`get_species`]
+///
+/// # Errors:
+/// Will return [`Err`] if the http connection could not be made,
+/// if the API responded with an error status code
+/// or if the response body contained invalid JSON.
+///
+/// Will return [`Ok(None)`] if the API returned a 404 status code
+pub async fn get_habitat(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    habitat_name: &str,
+) -> Result<Option<PokemonHabitat>, Box<dyn std::error::Error>> {
+    let habitat_name = habitat_name.to_lowercase();
+    let request = client.get(req.url_for("pokemon_habitat", [&habitat_name])?);
+    let fetch = move |cached| fetch(request, cached);
+    cache::get_or_fetch(req, |caches| &caches.habitats, &habitat_name, fetch).await
+}
+
+/// Any pokeapi resource that is only needed by name
+#[derive(Clone, Debug, Deserialize)]
+pub struct NamedResource {
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ResourceList {
+    pub results: Vec<NamedResource>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct Habitat {
     pub name: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
+pub struct PokemonHabitat {
+    pub name: String,
+    pub pokemon_species: Vec<NamedResource>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Generation {
+    pub pokemon_species: Vec<NamedResource>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct FlavorText {
     pub flavor_text: String,
     pub language: Language,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct Language {
     pub name: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct Species {
     pub name: String,
     pub is_legendary: bool,
-    pub habitat: Habitat,
+    /// pokeapi only has habitats for species up to generation III
+    pub habitat: Option<Habitat>,
     pub flavor_text_entries: Vec<FlavorText>,
+    pub evolution_chain: Option<EvolutionChainRef>,
+}
+
+impl Species {
+    /// The id of this species' evolution chain, taken from the end of the chain url.
+    ///
+    /// Only the id is used so that the chain is fetched from the configured pokeapi,
+    /// rather than whichever host the url happens to point at
+    pub fn evolution_chain_id(&self) -> Option<&str> {
+        self.evolution_chain
+            .as_ref()?
+            .url
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .filter(|id| !id.is_empty())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EvolutionChainRef {
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EvolutionChain {
+    pub chain: ChainLink,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChainLink {
+    pub species: NamedResource,
+    /// The conditions for evolving into this species.
+    /// Kept as raw JSON since pokeapi adds new kinds of condition over time
+    pub evolution_details: Vec<Map<String, Value>>,
+    pub evolves_to: Vec<ChainLink>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Pokemon {
+    pub name: String,
+    /// Height in decimetres
+    pub height: u32,
+    /// Weight in hectograms
+    pub weight: u32,
+    pub types: Vec<PokemonType>,
+    pub abilities: Vec<PokemonAbility>,
+    pub stats: Vec<PokemonStat>,
+    pub sprites: Sprites,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PokemonType {
+    pub slot: u32,
+    #[serde(rename = "type")]
+    pub type_: NamedResource,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PokemonAbility {
+    pub slot: u32,
+    pub is_hidden: bool,
+    pub ability: NamedResource,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PokemonStat {
+    pub base_stat: u32,
+    pub stat: NamedResource,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Sprites {
+    pub front_default: Option<String>,
 }
@@ -0,0 +1,207 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::HttpRequest;
+use futures::{stream, StreamExt};
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use reqwest_middleware::ClientWithMiddleware;
+use serde::Deserialize;
+
+use crate::pokemon::{self, NamedResource, Species};
+
+/// How many species to look up while searching for a legendary before giving up
+const MAX_LOOKUPS: usize = 64;
+
+/// How many species are looked up at once while searching for a legendary
+const LOOKUP_CONCURRENCY: usize = 8;
+
+/// The search for a legendary gave up before checking every candidate,
+/// so there may be one that wasn't found
+#[derive(Debug)]
+pub struct GaveUp;
+
+impl fmt::Display for GaveUp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no legendary in the first {} species looked up",
+            MAX_LOOKUPS
+        )
+    }
+}
+
+impl Error for GaveUp {}
+
+/// Restrictions on which species can be picked
+#[derive(Debug, Default, Deserialize)]
+pub struct Filters {
+    #[serde(default)]
+    pub legendary: bool,
+    pub habitat: Option<String>,
+    /// Generation name or number, eg `generation-i` or `1`
+    pub generation: Option<String>,
+}
+
+/// Pick a species matching the filters, using the rng to choose.
+///
+/// # Errors:
+/// Will return [`Err`] if any of the pokeapi requests fail,
+/// or [`GaveUp`] if too many species were looked up without finding a legendary.
+///
+/// Will return [`Ok(None)`] if the habitat or generation do not exist,
+/// or if no matching species could be found
+pub async fn pick_species(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    filters: &Filters,
+    rng: &mut impl Rng,
+) -> Result<Option<Species>, Box<dyn Error>> {
+    let mut candidates = match candidates(client, req, filters).await? {
+        Some(candidates) => candidates,
+        None => return Ok(None),
+    };
+    candidates.shuffle(rng);
+
+    // pokeapi can't list species by legendary status, so check them in turn,
+    // a few at a time. They're checked in order so the daily pick is the same everywhere.
+    // Without the filter the first candidate is picked, so only it is looked up
+    let concurrency = if filters.legendary {
+        LOOKUP_CONCURRENCY
+    } else {
+        1
+    };
+    let mut lookups = stream::iter(candidates.iter().take(MAX_LOOKUPS))
+        .map(|name| pokemon::get_species(client, req, name))
+        .buffered(concurrency);
+    while let Some(species) = lookups.next().await {
+        match species? {
+            Some(species) if species.is_legendary || !filters.legendary => {
+                return Ok(Some(species))
+            }
+            _ => {}
+        }
+    }
+
+    if candidates.len() > MAX_LOOKUPS {
+        return Err(GaveUp.into());
+    }
+    Ok(None)
+}
+
+/// The names of all species in the filtered habitat and generation, in pokeapi order
+async fn candidates(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    filters: &Filters,
+) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    let habitat = match &filters.habitat {
+        Some(habitat) => match pokemon::get_habitat(client, req, habitat).await? {
+            Some(habitat) => Some(habitat.pokemon_species),
+            None => return Ok(None),
+        },
+        None => None,
+    };
+
+    let generation = match &filters.generation {
+        Some(generation) => match pokemon::get_generation(client, req, generation).await? {
+            Some(generation) => Some(generation.pokemon_species),
+            None => return Ok(None),
+        },
+        None => None,
+    };
+
+    let names = |species: Vec<NamedResource>| species.into_iter().map(|s| s.name);
+
+    Ok(Some(match (habitat, generation) {
+        (Some(habitat), Some(generation)) => {
+            let generation: HashSet<_> = names(generation).collect();
+            names(habitat)
+                .filter(|name| generation.contains(name))
+                .collect()
+        }
+        (Some(species), None) | (None, Some(species)) => names(species).collect(),
+        (None, None) => names(pokemon::get_species_list(client, req).await?).collect(),
+    }))
+}
+
+/// A rng seeded from the seed and the current UTC date,
+/// so that every replica of the service makes the same picks for the day
+pub fn daily_rng(seed: &str) -> ChaCha8Rng {
+    let day = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / (24 * 60 * 60);
+
+    rng_for_day(seed, day)
+}
+
+/// The rng for the `day`th day since the unix epoch
+fn rng_for_day(seed: &str, day: u64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(fnv1a(format!("{}:{}", seed, day).as_bytes()))
+}
+
+/// Seconds until the UTC date changes, and with it the daily picks
+pub fn secs_until_next_day() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    (24 * 60 * 60 - now % (24 * 60 * 60)) as u32
+}
+
+/// 64 bit FNV-1a hash. Unlike `DefaultHasher`, the output is stable between builds
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{seq::SliceRandom, Rng};
+
+    use super::{fnv1a, rng_for_day};
+
+    #[test]
+    fn fnv1a_known_values() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn same_picks_everywhere() {
+        // fixed values rather than comparing two rngs, so a build that picks differently
+        // from the replicas it's deployed alongside fails here
+        let mut rng = rng_for_day("seed", 19000);
+        assert_eq!(rng.gen::<u64>(), 12148448474003489357);
+        let mut names = [
+            "bulbasaur",
+            "ivysaur",
+            "venusaur",
+            "charmander",
+            "charmeleon",
+        ];
+        names.shuffle(&mut rng);
+        assert_eq!(
+            names,
+            [
+                "charmeleon",
+                "bulbasaur",
+                "venusaur",
+                "charmander",
+                "ivysaur"
+            ]
+        );
+        assert_ne!(
+            rng_for_day("seed", 19001).gen::<u64>(),
+            rng_for_day("seed", 19000).gen::<u64>()
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::ServiceRequest,
+    http::{
+        header::{HeaderName, HeaderValue, RETRY_AFTER},
+        HeaderMap,
+    },
+    HttpMessage, HttpRequest, HttpResponse,
+};
+
+use crate::{
+    auth::{Consumer, Scope},
+    health::PROBE_PATHS,
+    reload::Live,
+};
+
+/// Limits are counted over fixed windows of this length
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// The most clients tracked at once. Once reached, windows that have ended are cleared out,
+/// and if every window is still going, the one closest to ending is dropped for a new client
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Requests per minute allowed for each client. `None` means unlimited
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// For the untranslated endpoints
+    pub pokemon: Option<u32>,
+    /// For anything that goes through funtranslations, which has a small shared quota
+    pub translated: Option<u32>,
+}
+
+/// Limits the rate of requests from each client, identified by API key or IP address.
+/// Shared between the server's workers.
+///
+/// The limits and trusted proxies are read from the live settings. Proxies in `trusted_proxies`
+/// set `X-Forwarded-For`, so the client address is taken from that header for requests coming
+/// through them
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    live: Live,
+    windows: Arc<Mutex<HashMap<(Scope, String), Window>>>,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+/// A client's usage of its limit, after counting the current request
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    limit: u32,
+    remaining: u32,
+    reset: Duration,
+    exceeded: bool,
+}
+
+impl From<Live> for RateLimiter {
+    fn from(live: Live) -> Self {
+        RateLimiter {
+            live,
+            windows: Arc::default(),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Count the request against its client's limit, keeping the client and quota
+    /// in the request's extensions for [`RateLimiter::charge`] and [`latest_quota`].
+    /// Returns `None` if the request isn't rate limited.
+    /// Health probes are never limited
+    pub fn check(&self, req: &ServiceRequest) -> Option<Quota> {
+        if PROBE_PATHS.contains(&req.path()) {
+            return None;
+        }
+        let scope = Scope::of(req.path(), req.query_string());
+        let client = {
+            let current = self.live.get();
+            let consumer = req.extensions().get::<Consumer>().cloned();
+            client(consumer, req.peer_addr()?, req.headers(), &current.trusted_proxies)
+        };
+        req.extensions_mut().insert(Client(client.clone()));
+
+        let quota = self.count(scope, client, 1)?;
+        req.extensions_mut().insert(quota);
+        Some(quota)
+    }
+
+    /// Count `n` more requests against the limit of the client that made `req`,
+    /// for requests that do the work of several, like translating a whole habitat
+    pub fn charge(&self, req: &HttpRequest, scope: Scope, n: u32) -> Option<Quota> {
+        if n == 0 {
+            return None;
+        }
+        let Client(client) = req.extensions().get::<Client>()?.clone();
+        let quota = self.count(scope, client, n)?;
+        req.extensions_mut().insert(quota);
+        Some(quota)
+    }
+
+    fn count(&self, scope: Scope, client: String, n: u32) -> Option<Quota> {
+        let limits = self.live.get().limits;
+        let limit = match scope {
+            Scope::Translated => limits.translated?,
+            Scope::Pokemon => limits.pokemon?,
+        };
+
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let key = (scope, client);
+        if windows.len() >= MAX_TRACKED_CLIENTS && !windows.contains_key(&key) {
+            windows.retain(|_, window| now.duration_since(window.start) < WINDOW);
+            // a burst of new clients, maybe spoofing X-Forwarded-For, mustn't grow the map
+            if windows.len() >= MAX_TRACKED_CLIENTS {
+                let oldest = windows
+                    .iter()
+                    .min_by_key(|(_, window)| window.start)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    windows.remove(&oldest);
+                }
+            }
+        }
+
+        let window = windows.entry(key).or_insert(Window {
+            start: now,
+            count: 0,
+        });
+        if now.duration_since(window.start) >= WINDOW {
+            *window = Window {
+                start: now,
+                count: 0,
+            };
+        }
+        window.count = window.count.saturating_add(n);
+
+        Some(Quota {
+            limit,
+            remaining: limit.saturating_sub(window.count),
+            reset: WINDOW - now.duration_since(window.start),
+            exceeded: window.count > limit,
+        })
+    }
+}
+
+/// The client a request was counted against
+#[derive(Clone, Debug)]
+struct Client(String);
+
+/// The quota left after everything the request has been charged for
+pub fn latest_quota(req: &HttpRequest) -> Option<Quota> {
+    req.extensions().get::<Quota>().copied()
+}
+
+/// Identify the client by the label of their API key, or otherwise their IP address
+fn client(
+    consumer: Option<Consumer>,
+    peer: SocketAddr,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpAddr],
+) -> String {
+    match consumer {
+        Some(Consumer(label)) => format!("key:{}", label),
+        None => format!("ip:{}", client_ip(peer.ip(), headers, trusted_proxies)),
+    }
+}
+
+/// The peer address, unless it's a trusted proxy.
+/// Then it's the last address in `X-Forwarded-For` that isn't a trusted proxy.
+/// Addresses before one that can't be parsed aren't trusted, as it wasn't added by a trusted proxy
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+
+    for addr in forwarded.into_iter().rev() {
+        match addr.trim().parse() {
+            Ok(addr) if trusted_proxies.contains(&addr) => continue,
+            Ok(addr) => return addr,
+            Err(_) => break,
+        }
+    }
+    peer
+}
+
+impl Quota {
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    /// Add the `RateLimit-*` headers describing the quota to a response
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        let reset = self.reset_secs().to_string();
+        for (name, value) in [
+            ("ratelimit-limit", self.limit.to_string()),
+            ("ratelimit-remaining", self.remaining.to_string()),
+            ("ratelimit-reset", reset),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+    }
+
+    /// The response to send once the quota is exceeded
+    pub fn too_many_requests(&self) -> HttpResponse {
+        let mut res = HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, self.reset_secs()))
+            .body("rate limit exceeded");
+        self.insert_headers(res.headers_mut());
+        res
+    }
+
+    /// Whole seconds until the window resets, rounded up so clients don't retry too early
+    fn reset_secs(&self) -> u64 {
+        self.reset.as_secs() + u64::from(self.reset.subsec_nanos() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Limits, RateLimiter, Window, MAX_TRACKED_CLIENTS};
+    use crate::{
+        auth::Scope,
+        reload::{Current, Live},
+    };
+
+    #[test]
+    fn tracked_clients_capped() {
+        let limiter = RateLimiter::from(Live::new(Current {
+            limits: Limits {
+                pokemon: Some(1),
+                translated: None,
+            },
+            ..Current::default()
+        }));
+        let now = Instant::now();
+        let windows = (0..MAX_TRACKED_CLIENTS).map(|i| {
+            let window = Window {
+                start: now - Duration::from_millis(i as u64),
+                count: 1,
+            };
+            ((Scope::Pokemon, i.to_string()), window)
+        });
+        limiter.windows.lock().unwrap().extend(windows);
+
+        // every window is still going, so the one that started first makes way
+        let quota = limiter.count(Scope::Pokemon, "new".to_owned(), 1).unwrap();
+        assert!(!quota.exceeded);
+        let windows = limiter.windows.lock().unwrap();
+        assert_eq!(windows.len(), MAX_TRACKED_CLIENTS);
+        let oldest = (Scope::Pokemon, (MAX_TRACKED_CLIENTS - 1).to_string());
+        assert!(!windows.contains_key(&oldest));
+        assert!(windows.contains_key(&(Scope::Pokemon, "0".to_owned())));
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::rt;
+use tracing::{info, warn};
+
+use crate::{
+    auth::Keys,
+    config::{self, Config},
+    mirrors::Groups,
+    ratelimit::Limits,
+};
+
+/// How often the config and API keys files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The settings that are applied to the running service. Changes to the others need a restart
+const RELOADABLE: &[&str] = &[
+    "rate_limit",
+    "translated_rate_limit",
+    "trusted_proxies",
+    "cors_allowed_origins",
+    "pokemon_url",
+    "translations_url",
+    "mirror_strategy",
+    "mirror_cooldown",
+];
+
+/// The settings of the running service that reloading changes
+#[derive(Debug, Default)]
+pub struct Current {
+    /// `None` if authentication is disabled, which needs a restart to enable
+    pub api_keys: Option<Keys>,
+    pub limits: Limits,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub cors_origins: Vec<String>,
+    pub mirrors: Groups,
+}
+
+impl Current {
+    /// # Errors:
+    /// If the API keys file can't be read or isn't a valid keys file
+    pub fn load(config: &Config) -> Result<Self, Box<dyn Error>> {
+        Ok(Current {
+            api_keys: read_keys(config.api_keys_file.as_deref())?,
+            limits: config.limits(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            cors_origins: config.cors_allowed_origins.clone(),
+            mirrors: Groups::new(config.mirrors()),
+        })
+    }
+}
+
+/// The current settings, shared with the parts of the running service that use them.
+/// Reloading swaps them all at once, so a request never sees some of the old settings and some of the new
+#[derive(Clone, Debug, Default)]
+pub struct Live(Arc<RwLock<Arc<Current>>>);
+
+impl Live {
+    pub fn new(current: Current) -> Self {
+        Live(Arc::new(RwLock::new(Arc::new(current))))
+    }
+
+    pub fn get(&self) -> Arc<Current> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn set(&self, current: Current) {
+        *self.0.write().unwrap() = Arc::new(current);
+    }
+}
+
+/// Reloads the config, and the API keys, into the running service.
+/// New settings are checked before any are applied, so an invalid config leaves the current one running
+pub struct Reloader {
+    live: Live,
+    /// The API keys file the service started with, if authentication is enabled
+    api_keys_file: Option<PathBuf>,
+    /// Whether CORS is enabled, which needs a restart to change
+    cors_enabled: bool,
+    /// The config and API keys files, if there are any
+    files: Vec<PathBuf>,
+    state: Mutex<State>,
+}
+
+struct State {
+    settings: Settings,
+    /// When each of the files was last modified
+    modified: Vec<Option<SystemTime>>,
+}
+
+/// The settings as `config check` prints them, so secrets are never logged,
+/// along with the secrets themselves to tell when they change
+struct Settings {
+    redacted: toml::value::Table,
+    secrets: BTreeMap<&'static str, String>,
+}
+
+/// A setting that changed, with its old and new values. Unset values are `None`
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub key: String,
+    pub old: Option<toml::Value>,
+    pub new: Option<toml::Value>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // a secret that changed is redacted the same before and after
+        if self.old == self.new {
+            return write!(f, "{}: changed", self.key);
+        }
+        let value = |value: &Option<toml::Value>| match value {
+            Some(value) => value.to_string(),
+            None => "unset".to_owned(),
+        };
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.key,
+            value(&self.old),
+            value(&self.new)
+        )
+    }
+}
+
+impl Reloader {
+    /// # Errors:
+    /// If the settings can't be serialized to compare with new ones
+    pub fn new(config: &Config, live: Live, cors_enabled: bool) -> Result<Self, Box<dyn Error>> {
+        let files: Vec<PathBuf> = config
+            .config
+            .iter()
+            .chain(&config.api_keys_file)
+            .cloned()
+            .collect();
+        let state = State {
+            settings: settings(config)?,
+            modified: files.iter().map(|file| last_modified(file)).collect(),
+        };
+        Ok(Reloader {
+            live,
+            api_keys_file: config.api_keys_file.clone(),
+            cors_enabled,
+            files,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Parse the config again, from the same arguments and environment, and apply it
+    ///
+    /// # Errors:
+    /// If the new config is invalid, in which case the current one is kept
+    pub fn reload(&self) -> Result<Vec<Change>, Box<dyn Error>> {
+        self.apply(config::parse()?)
+    }
+
+    /// Apply the reloadable settings of `config`, and reload the API keys.
+    /// Returns every setting that changed, including those that need a restart
+    ///
+    /// # Errors:
+    /// If the API keys file can't be read, in which case nothing is changed
+    pub fn apply(&self, config: Config) -> Result<Vec<Change>, Box<dyn Error>> {
+        let new_settings = settings(&config)?;
+        let mut state = self.state.lock().unwrap();
+
+        // everything is read before it's swapped in, so a failure changes nothing
+        let current = self.live.get();
+        self.live.set(Current {
+            api_keys: read_keys(self.api_keys_file.as_deref())?,
+            limits: config.limits(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            cors_origins: config.cors_allowed_origins.clone(),
+            mirrors: current.mirrors.updated(config.mirrors()),
+        });
+
+        let changes = diff(&state.settings, &new_settings);
+        state.settings = new_settings;
+        Ok(changes)
+    }
+
+    /// Whether a changed setting only takes effect after a restart
+    fn needs_restart(&self, change: &Change) -> bool {
+        let key = change.key.as_str();
+        !RELOADABLE.contains(&key)
+            || (key == "cors_allowed_origins" && !self.cors_enabled)
+    }
+
+    /// Reload, logging the changes or why the new config was rejected
+    fn reload_and_log(&self, trigger: &str) {
+        let changes = match self.reload() {
+            Ok(changes) => changes,
+            Err(err) => {
+                warn!(%err, trigger, "invalid config, keeping the current one");
+                return;
+            }
+        };
+
+        let (mut applied, mut restart) = (Vec::new(), Vec::new());
+        for change in &changes {
+            if self.needs_restart(change) {
+                restart.push(change.to_string());
+            } else {
+                applied.push(change.to_string());
+            }
+        }
+        info!(trigger, changes = %applied.join(", "), "reloaded config");
+        if !restart.is_empty() {
+            warn!(changes = %restart.join(", "), "some changed settings need a restart to apply");
+        }
+    }
+
+    /// Note the files' modification times, returning whether any changed since they were last noted
+    fn files_changed(&self) -> bool {
+        let modified: Vec<_> = self.files.iter().map(|file| last_modified(file)).collect();
+        let mut state = self.state.lock().unwrap();
+        if modified == state.modified {
+            return false;
+        }
+        state.modified = modified;
+        true
+    }
+}
+
+/// Reload each time the process receives SIGHUP
+pub async fn reload_on_hangup(reloader: Arc<Reloader>) {
+    let mut hangup = match rt::signal::unix::signal(rt::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            warn!(%err, "unable to listen for SIGHUP, config won't be reloaded");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        reloader.reload_and_log("SIGHUP");
+    }
+}
+
+/// Periodically reload when the config or API keys files change
+pub async fn reload_on_change(reloader: Arc<Reloader>) {
+    if reloader.files.is_empty() {
+        return;
+    }
+    loop {
+        rt::time::sleep(RELOAD_INTERVAL).await;
+        if reloader.files_changed() {
+            reloader.reload_and_log("file changed");
+        }
+    }
+}
+
+fn settings(config: &Config) -> Result<Settings, Box<dyn Error>> {
+    Ok(Settings {
+        redacted: toml::from_str(&config.redacted()?)?,
+        secrets: config.secrets().into_iter().collect(),
+    })
+}
+
+/// The settings that differ between `old` and `new`, in order
+fn diff(old: &Settings, new: &Settings) -> Vec<Change> {
+    let (old_values, new_values) = (&old.redacted, &new.redacted);
+    let mut keys: Vec<&String> = old_values.keys().chain(new_values.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter(|key| {
+            old_values.get(*key) != new_values.get(*key)
+                || old.secrets.get(key.as_str()) != new.secrets.get(key.as_str())
+        })
+        .map(|key| Change {
+            key: key.clone(),
+            old: old_values.get(key).cloned(),
+            new: new_values.get(key).cloned(),
+        })
+        .collect()
+}
+
+fn read_keys(path: Option<&Path>) -> Result<Option<Keys>, Box<dyn Error>> {
+    path.map(Keys::read).transpose()
+}
+
+fn last_modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use structopt::StructOpt;
+
+    use super::{diff, settings, Change, Live, Reloader};
+    use crate::config::Config;
+
+    fn config(args: &[&str]) -> Config {
+        Config::from_iter(std::iter::once(&"pokefun").chain(args))
+    }
+
+    #[test]
+    fn diff_settings() {
+        let old = settings(&config(&["--rate-limit=10", "--port=1"])).unwrap();
+        let new = settings(&config(&["--translated-rate-limit=5", "--port=2"])).unwrap();
+        let changes: Vec<_> = diff(&old, &new).iter().map(Change::to_string).collect();
+        assert_eq!(
+            changes,
+            vec![
+                "port: 1 -> 2",
+                "rate_limit: 10 -> unset",
+                "translated_rate_limit: unset -> 5",
+            ]
+        );
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn diff_secrets() {
+        let old = settings(&config(&["--daily-seed=old"])).unwrap();
+        let new = settings(&config(&["--daily-seed=new"])).unwrap();
+        let changes: Vec<_> = diff(&old, &new).iter().map(Change::to_string).collect();
+        assert_eq!(changes, vec!["daily_seed: changed"]);
+    }
+
+    #[test]
+    fn apply_settings() {
+        let live = Live::default();
+        let reloader = Reloader::new(&config(&[]), live.clone(), true).unwrap();
+
+        let changes = reloader
+            .apply(config(&[
+                "--cors-allowed-origins=https://example.com",
+                "--rate-limit=5",
+                "--daily-seed=secret",
+                "--port=1",
+            ]))
+            .unwrap();
+        assert_eq!(live.get().cors_origins, vec!["https://example.com"]);
+        assert_eq!(live.get().limits.pokemon, Some(5));
+        let restart: Vec<_> = changes
+            .iter()
+            .filter(|change| reloader.needs_restart(change))
+            .map(|change| change.key.as_str())
+            .collect();
+        assert_eq!(restart, vec!["daily_seed", "port"]);
+    }
+
+    #[test]
+    fn apply_nothing_on_error() {
+        let path = env::temp_dir().join("pokefun-reload-keys.json");
+        fs::write(&path, "[]").unwrap();
+        let args = ["--api-keys-file", path.to_str().unwrap()];
+        let live = Live::default();
+        let reloader = Reloader::new(&config(&args), live.clone(), false).unwrap();
+
+        // the keys file is broken, so the new rate limit isn't applied either
+        fs::write(&path, "not json").unwrap();
+        let result = reloader.apply(config(&[&args[..], &["--rate-limit=5"]].concat()));
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+        assert_eq!(live.get().limits.pokemon, None);
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use reqwest::{
+    header::{CONTENT_LENGTH, TRANSFER_ENCODING},
+    Request, Response, StatusCode,
+};
+use reqwest_middleware::{Middleware, Next};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use task_local_extensions::Extensions;
+use tracing::{error, warn};
+
+/// Whether upstream responses are recorded to, or replayed from, a directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+/// An upstream response, as saved in the replay directory
+#[derive(Debug, Serialize, Deserialize)]
+struct Recording {
+    method: String,
+    /// The url without its query
+    url: String,
+    query: Vec<(String, String)>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+/// Bodies are saved as JSON where possible, so recordings are easy to read and edit
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Body {
+    Json(Value),
+    Text(String),
+}
+
+/// Saves every upstream response into a directory, for [`Replayer`] to serve later
+#[derive(Debug)]
+pub struct Recorder {
+    dir: PathBuf,
+}
+
+impl Recorder {
+    /// # Errors:
+    /// If the directory doesn't exist and can't be created
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Recorder {
+            dir: dir.to_owned(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Recorder {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let path = self.dir.join(file_name(&req));
+        let method = req.method().to_string();
+        let mut url = req.url().clone();
+        let query = url.query_pairs().into_owned().collect();
+        url.set_query(None);
+
+        let resp = next.run(req, extensions).await?;
+        // revalidations would overwrite the full response with an empty 304
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(resp);
+        }
+
+        let status = resp.status().as_u16();
+        // the body may be reformatted, so its length isn't kept
+        let headers = resp
+            .headers()
+            .iter()
+            .filter(|(name, _)| ![CONTENT_LENGTH, TRANSFER_ENCODING].contains(name))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect();
+        let bytes = resp.bytes().await?;
+        let body = match serde_json::from_slice(&bytes) {
+            Ok(json) => Body::Json(json),
+            Err(_) => Body::Text(String::from_utf8_lossy(&bytes).into_owned()),
+        };
+
+        let recording = Recording {
+            method,
+            url: url.to_string(),
+            query,
+            status,
+            headers,
+            body,
+        };
+        if let Err(err) = save(&path, &recording) {
+            warn!(%err, ?path, "error saving recording");
+        }
+        recording.into_response()
+    }
+}
+
+/// Serves upstream responses from a directory saved by [`Recorder`], without making any requests.
+/// Requests that weren't recorded fail
+#[derive(Debug)]
+pub struct Replayer {
+    dir: PathBuf,
+}
+
+impl Replayer {
+    /// # Errors:
+    /// If the directory doesn't exist
+    pub fn new(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        if !dir.is_dir() {
+            return Err(format!("replay directory {:?} does not exist", dir).into());
+        }
+        Ok(Replayer {
+            dir: dir.to_owned(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Replayer {
+    async fn handle(
+        &self,
+        req: Request,
+        _extensions: &mut Extensions,
+        _next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let path = self.dir.join(file_name(&req));
+        let recording = fs::read(&path)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|json| Ok(serde_json::from_slice::<Recording>(&json)?));
+
+        match recording {
+            Ok(recording) => recording.into_response(),
+            Err(err) => {
+                error!(%err, ?path, url = %req.url(), "no recording for upstream request");
+                let message = format!(
+                    "no recording of {} {} in {:?}",
+                    req.method(),
+                    req.url(),
+                    path
+                );
+                Err(reqwest_middleware::Error::middleware(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    message,
+                )))
+            }
+        }
+    }
+}
+
+impl Recording {
+    fn into_response(self) -> reqwest_middleware::Result<Response> {
+        let mut builder = http::Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let body = match self.body {
+            Body::Json(json) => {
+                serde_json::to_vec(&json).map_err(reqwest_middleware::Error::middleware)?
+            }
+            Body::Text(text) => text.into_bytes(),
+        };
+        Ok(builder
+            .body(body)
+            .map_err(reqwest_middleware::Error::middleware)?
+            .into())
+    }
+}
+
+/// The file a request's response is saved in.
+/// Named after the url to be recognisable, with a hash of the method and full url to be unique
+fn file_name(req: &Request) -> String {
+    let url = req.url();
+    let readable: String = format!("{}{}", url.host_str().unwrap_or_default(), url.path())
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    let hash = Sha256::digest(format!("{} {}", req.method(), url).as_bytes());
+    format!("{}-{:.16x}.json", readable.trim_end_matches('_'), hash)
+}
+
+fn save(path: &Path, recording: &Recording) -> Result<(), Box<dyn Error>> {
+    fs::write(path, serde_json::to_vec_pretty(recording)?)?;
+    Ok(())
+}
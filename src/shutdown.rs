@@ -0,0 +1,67 @@
+use std::{io, process, time::Duration};
+
+use actix_web::{
+    dev::Server,
+    rt::{
+        self,
+        signal::unix::{signal, Signal, SignalKind},
+    },
+};
+use futures::future::{select, Either};
+use tracing::{info, warn};
+
+use crate::health::Draining;
+
+/// The signals asking the process to stop, listened for from startup
+pub struct Signals {
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl Signals {
+    /// # Errors:
+    /// If the signal handlers can't be installed
+    pub fn listen() -> io::Result<Self> {
+        Ok(Signals {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    /// Resolves once the process is asked to stop, with the name of the signal
+    async fn recv(&mut self) -> &'static str {
+        let terminate = Box::pin(self.terminate.recv());
+        let interrupt = Box::pin(self.interrupt.recv());
+        match select(terminate, interrupt).await {
+            Either::Left(_) => "SIGTERM",
+            Either::Right(_) => "SIGINT",
+        }
+    }
+}
+
+/// Wait for SIGTERM or SIGINT, then shut the servers down gracefully.
+///
+/// `/readyz` starts failing `delay` before we stop accepting connections, so load balancers have
+/// time to notice and stop sending us requests. The servers then give in-flight requests their
+/// shutdown timeout to finish. A second signal exits straight away
+pub async fn on_signal(
+    mut signals: Signals,
+    draining: Draining,
+    delay: Duration,
+    servers: Vec<Server>,
+) {
+    let signal = signals.recv().await;
+    info!(signal, ?delay, "shutting down, draining connections");
+    draining.start();
+
+    let stop = Box::pin(async move {
+        rt::time::sleep(delay).await;
+        for server in servers {
+            server.stop(true).await;
+        }
+    });
+    if let Either::Right((signal, _)) = select(stop, Box::pin(signals.recv())).await {
+        warn!(signal, "received a second signal, exiting without draining");
+        process::exit(1);
+    }
+}
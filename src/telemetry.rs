@@ -0,0 +1,71 @@
+use actix_web::{dev::ServiceRequest, http::HeaderMap};
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::access_log::RequestId;
+
+/// Install a global tracer that exports spans over OTLP/HTTP to the collector at `endpoint`.
+///
+/// Also sets up W3C trace context propagation, so traces continue from incoming
+/// `traceparent` headers and on to the upstream APIs
+pub fn init_tracer(endpoint: &str) -> Result<trace::Tracer, TraceError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')));
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )])),
+        )
+        .install_batch(opentelemetry::runtime::TokioCurrentThread)
+}
+
+/// Create the span for handling an incoming request,
+/// continuing the caller's trace if they sent a `traceparent` header.
+/// Every span created while handling the request is a child of this one, so carries its request id
+pub fn request_span(req: &ServiceRequest, request_id: &RequestId) -> Span {
+    let span = tracing::info_span!(
+        "HTTP request",
+        request_id = %request_id,
+        http.method = %req.method(),
+        http.target = %req.uri(),
+        otel.kind = "server",
+        otel.name = tracing::field::Empty,
+        http.status_code = tracing::field::Empty,
+    );
+
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&RequestHeaders(req.headers()))
+    });
+    span.set_parent(parent);
+
+    span
+}
+
+/// Adapts actix headers for reading by an opentelemetry propagator
+struct RequestHeaders<'a>(&'a HeaderMap);
+
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
@@ -1,25 +1,61 @@
 use actix_http::{Method, Request};
 use actix_web::{
     dev::{self, Service, ServiceResponse},
-    test, Error,
+    http::HeaderName,
+    test, web, App, Error,
 };
+use futures::FutureExt;
 use lazy_static::lazy_static;
-use mockito::{Matcher, mock};
-use reqwest::{Client, StatusCode};
-use reqwest_middleware::ClientBuilder;
-use reqwest_tracing::TracingMiddleware;
-
-use crate::{api::PokemonInfo, new_service, AppConfig, APP_CONFIG};
+use mockito::{Matcher, Mock, mock};
+use opentelemetry::{
+    global,
+    sdk::{propagation::TraceContextPropagator, trace::TracerProvider},
+    trace::{Tracer, TracerProvider as _},
+};
+use reqwest::StatusCode;
+use structopt::StructOpt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{
+    api::{
+        Ability, Evolution, EvolutionTrigger, HabitatInfo, PokemonInfo, PokemonStats,
+        TranslationInfo,
+    },
+    auth::{ApiKeys, Keys},
+    cache::{Caches, Staleness},
+    config::{self, Config, Readiness},
+    cors::CorsConfig,
+    egress::{Egress, EgressConfig},
+    fake_upstream::{self, Fake, Quota},
+    health::{Draining, UpstreamChecks},
+    mirrors::{Groups, Mirrors, MirrorsConfig, Strategy},
+    timeouts::{Timeouts, UpstreamTimeouts},
+    tls::{self, HttpsPort},
+    new_client, new_service, replay, UpstreamConfig,
+    ratelimit::{Limits, RateLimiter}, reload::{Current, Live, Reloader}, telemetry, AppConfig, APP_CONFIG,
+};
 
-use std::sync::Once;
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Once,
+    time::Duration,
+};
 
 static TRACING: Once = Once::new();
 
 fn setup_tracing() {
     TRACING.call_once(|| {
+        // an opentelemetry tracer that exports nowhere, so trace context can still be propagated
+        let tracer = TRACER_PROVIDER.tracer("test", None);
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
         tracing_subscriber::fmt()
             .with_env_filter("trace")
             .with_test_writer()
+            .finish()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
             .init();
     });
 }
@@ -27,34 +63,134 @@ fn setup_tracing() {
 async fn create_test_app(
     app_config: &AppConfig,
 ) -> impl Service<Request, Response = ServiceResponse<dev::AnyBody>, Error = Error> {
-    setup_tracing();
+    TestApp {
+        config: app_config.clone(),
+        ..TestApp::default()
+    }
+    .init()
+    .await
+}
+
+/// The parts of a test app, for tests that need more than the config changing.
+/// The default is the mock config, without authentication or rate limits
+struct TestApp {
+    config: AppConfig,
+    upstream: UpstreamConfig,
+    draining: Draining,
+    upstream_checks: UpstreamChecks,
+    api_keys: ApiKeys,
+    rate_limiter: RateLimiter,
+    caches: Caches,
+}
 
-    let client = Client::builder()
-        .build()
-        .expect("client build successfully");
-    let client = ClientBuilder::new(client).with(TracingMiddleware).build();
+impl Default for TestApp {
+    fn default() -> Self {
+        TestApp {
+            config: MOCK_CONFIG.clone(),
+            upstream: UpstreamConfig::default(),
+            draining: Draining::default(),
+            upstream_checks: UpstreamChecks::default(),
+            api_keys: ApiKeys::default(),
+            rate_limiter: RateLimiter::default(),
+            caches: Caches::default(),
+        }
+    }
+}
+
+impl TestApp {
+    async fn init(
+        self,
+    ) -> impl Service<Request, Response = ServiceResponse<dev::AnyBody>, Error = Error> {
+        setup_tracing();
+
+        let client = new_client(self.upstream).expect("client build successfully");
+
+        test::init_service(new_service(
+            client,
+            &self.config,
+            self.draining,
+            self.upstream_checks,
+            self.api_keys,
+            self.rate_limiter,
+            self.caches,
+        )).await
+    }
+}
 
-    test::init_service(new_service(client, app_config)).await
+/// An app using the production APIs, for the ignored live tests.
+/// Set `RECORD_DIR` to record the responses, or `REPLAY_DIR` to run the tests offline from a recording
+async fn create_live_test_app(
+) -> impl Service<Request, Response = ServiceResponse<dev::AnyBody>, Error = Error> {
+    // the settings are read from the environment, as the server reads them
+    let config = config::parse_from(vec!["pokefun".into()], &std::env::vars_os().collect())
+        .expect("valid settings");
+
+    TestApp {
+        config: APP_CONFIG.clone(),
+        upstream: UpstreamConfig {
+            replay: config.replay_mode(),
+            ..UpstreamConfig::default()
+        },
+        ..TestApp::default()
+    }
+    .init()
+    .await
 }
 
 lazy_static! {
+    // tracers only hold a weak reference to their provider, so it must outlive the tests
+    static ref TRACER_PROVIDER: TracerProvider = TracerProvider::builder().build();
+
     static ref MOCK_CONFIG: AppConfig = {
         AppConfig {
             pokemon_url: mockito::server_url().into(),
             translations_url: mockito::server_url().into(),
+            daily_seed: "".into(),
+            max_translation_length: 1000,
+            cache_max_age: 3600,
+            translated_cache_max_age: 300,
+            request_deadline: Duration::from_secs(10),
+            serve_metrics: true,
+            readiness: Readiness::Off,
+            readiness_ttl: Duration::from_secs(10),
+            cors: None,
         }
     };
 }
 
+/// The fixtures in `replays/`, served without faults
+fn fixtures() -> Fake {
+    Fake::load("replays".as_ref()).expect("valid fixtures")
+}
+
+/// Serve `fake` on its own port as both pokeapi and funtranslations, returning the server
+/// and a config using it. Unlike mockito's shared server, tests can't answer each other's requests
+fn fake_upstream(fake: Fake) -> (dev::Server, AppConfig) {
+    let fake = web::Data::new(fake);
+    let server = actix_web::HttpServer::new(move || {
+        App::new().app_data(fake.clone()).configure(fake_upstream::configure)
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .expect("server binds");
+    let url = format!("http://127.0.0.1:{}", server.addrs()[0].port());
+    let server = server.run();
+    actix_rt::spawn(server.clone().map(drop));
+
+    let config = AppConfig {
+        pokemon_url: url.clone().into(),
+        translations_url: url.into(),
+        ..MOCK_CONFIG.clone()
+    };
+    (server, config)
+}
+
 #[actix_rt::test]
 async fn get_pokemon_ok_mocked() {
-    let _m = mock("GET", "/api/v2/pokemon-species/mewtwo/")
-        .with_status(200)
-        .with_header("content-type", "application/json")
-        .with_body_from_file("replays/mewtwo.json")
-        .create();
+    let (upstream, config) = fake_upstream(fixtures());
 
-    let app = create_test_app(&MOCK_CONFIG).await;
+    let app = create_test_app(&config).await;
 
     let req = test::TestRequest::with_uri("/pokemon/mewtwo")
         .method(Method::GET)
@@ -71,16 +207,16 @@ async fn get_pokemon_ok_mocked() {
         description: "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.".into(),
         is_legendary: true,
         habitat: "rare".into(),
-    })
+    });
+
+    upstream.stop(false).await;
 }
 
 #[actix_rt::test]
 async fn get_pokemon_not_found_mocked() {
-    let _m1 = mock("GET", "/api/v2/pokemon-species/mewthree/")
-        .with_status(404)
-        .create();
+    let (upstream, config) = fake_upstream(fixtures());
 
-    let app = create_test_app(&MOCK_CONFIG).await;
+    let app = create_test_app(&config).await;
 
     let req = test::TestRequest::with_uri("/pokemon/mewthree")
         .method(Method::GET)
@@ -89,277 +225,2630 @@ async fn get_pokemon_not_found_mocked() {
     let resp: ServiceResponse = app.call(req).await.expect("valid response");
 
     assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    upstream.stop(false).await;
 }
 
 #[actix_rt::test]
-async fn get_pokemon_translated_legendary_mocked() {
-    let _m1 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+async fn get_pokemon_not_modified_mocked() {
+    let _m = mock("GET", "/api/v2/pokemon-species/mewtwo/")
         .with_status(200)
         .with_header("content-type", "application/json")
         .with_body_from_file("replays/mewtwo.json")
         .create();
 
-    let _m2 = mock("GET", "/translate/yoda")
-        .match_query(Matcher::UrlEncoded("text".into(), "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.".into()))
-        .with_status(200)
-        .with_header("content-type", "application/json")
-        .with_body_from_file("replays/mewtwo_yoda.json")
-        .create();
-
     let app = create_test_app(&MOCK_CONFIG).await;
 
-    let req = test::TestRequest::with_uri("/pokemon/translated/mewtwo")
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
         .method(Method::GET)
         .to_request();
 
     let resp: ServiceResponse = app.call(req).await.expect("valid response");
 
     assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "public, max-age=3600");
+    let etag = resp.headers().get("etag").unwrap().clone();
 
-    let result: PokemonInfo = test::read_body_json(resp).await;
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::GET)
+        .insert_header(("if-none-match", etag.clone()))
+        .to_request();
 
-    assert_eq!(result, PokemonInfo {
-        name: "mewtwo".into(),
-        description: "Created by a scientist after years of horrific gene splicing and dna engineering experiments,  it was.".into(),
-        is_legendary: true,
-        habitat: "rare".into(),
-    })
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers().get("etag").unwrap(), &etag);
+    assert!(test::read_body(resp).await.is_empty());
 }
 
 #[actix_rt::test]
-async fn get_pokemon_translated_cave_mocked() {
-    let _m1 = mock("GET", "/api/v2/pokemon-species/zubat/")
+async fn get_pokemon_cached_mocked() {
+    let m = mock("GET", "/api/v2/pokemon-species/mewtwo/")
         .with_status(200)
         .with_header("content-type", "application/json")
-        .with_body_from_file("replays/zubat.json")
+        .with_header("cache-control", "public, max-age=86400")
+        .with_body_from_file("replays/mewtwo.json")
+        .expect(1)
         .create();
 
-    let _m2 = mock("GET", "/translate/yoda")
-        .match_query(Matcher::UrlEncoded("text".into(), "Forms colonies in perpetually dark places. Uses ultrasonic waves to identify and approach targets.".into()))
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    for _ in 0..2 {
+        let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+            .method(Method::GET)
+            .to_request();
+
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    m.assert();
+}
+
+#[actix_rt::test]
+async fn get_pokemon_revalidated_mocked() {
+    let m1 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .match_header("if-none-match", Matcher::Missing)
         .with_status(200)
         .with_header("content-type", "application/json")
-        .with_body_from_file("replays/zubat_yoda.json")
+        .with_header("cache-control", "public, max-age=0")
+        .with_header("etag", "\"v1\"")
+        .with_body_from_file("replays/mewtwo.json")
+        .expect(1)
+        .create();
+
+    let m2 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .match_header("if-none-match", "\"v1\"")
+        .with_status(304)
+        .with_header("cache-control", "public, max-age=0")
+        .expect(1)
         .create();
 
     let app = create_test_app(&MOCK_CONFIG).await;
 
-    let req = test::TestRequest::with_uri("/pokemon/translated/zubat")
-        .method(Method::GET)
-        .to_request();
+    for _ in 0..2 {
+        let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+            .method(Method::GET)
+            .to_request();
 
-    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
 
-    assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.status(), StatusCode::OK);
 
-    let result: PokemonInfo = test::read_body_json(resp).await;
+        let result: PokemonInfo = test::read_body_json(resp).await;
 
-    assert_eq!(result, PokemonInfo {
-        name: "zubat".into(),
-        description: "Forms colonies in perpetually dark places.Ultrasonic waves to identify and approach targets,  uses.".into(),
-        is_legendary: false,
-        habitat: "cave".into(),
-    })
+        assert_eq!(result.name, "mewtwo");
+    }
+
+    m1.assert();
+    m2.assert();
+}
+
+async fn create_test_app_with_staleness(
+    staleness: Staleness,
+) -> impl Service<Request, Response = ServiceResponse<dev::AnyBody>, Error = Error> {
+    TestApp {
+        caches: Caches::new(Duration::from_secs(3600), Duration::from_secs(3600), staleness),
+        ..TestApp::default()
+    }
+    .init()
+    .await
 }
 
 #[actix_rt::test]
-async fn get_pokemon_translated_other_mocked() {
-    let _m1 = mock("GET", "/api/v2/pokemon-species/ditto/")
+async fn get_pokemon_stale_while_revalidate_mocked() {
+    let m1 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .match_header("if-none-match", Matcher::Missing)
         .with_status(200)
         .with_header("content-type", "application/json")
-        .with_body_from_file("replays/ditto.json")
+        .with_header("cache-control", "public, max-age=0")
+        .with_header("etag", "\"v1\"")
+        .with_body_from_file("replays/mewtwo.json")
+        .expect(1)
         .create();
 
-    let _m2 = mock("GET", "/translate/shakespeare")
-        .match_query(Matcher::UrlEncoded("text".into(), "It can freely recombine its own cellular structure to transform into other life-forms.".into()))
-        .with_status(200)
-        .with_header("content-type", "application/json")
-        .with_body_from_file("replays/ditto_shakespeare.json")
+    let m2 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .match_header("if-none-match", "\"v1\"")
+        .with_status(304)
+        .expect(1)
         .create();
 
-    let app = create_test_app(&MOCK_CONFIG).await;
-
-    let req = test::TestRequest::with_uri("/pokemon/translated/ditto")
-        .method(Method::GET)
-        .to_request();
+    let app = create_test_app_with_staleness(Staleness {
+        while_revalidate: Duration::from_secs(60),
+        if_error: Duration::ZERO,
+    })
+    .await;
 
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
     let resp: ServiceResponse = app.call(req).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().get("warning").is_none());
 
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
     assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("warning").unwrap(), "110 - \"Response is Stale\"");
+    assert!(resp.headers().get("age").is_some());
+    // it can only be cached for as long as it can still be served stale
+    let cache_control = resp.headers().get("cache-control").unwrap().to_str().unwrap();
+    let max_age: u64 = cache_control.strip_prefix("public, max-age=").unwrap().parse().unwrap();
+    assert!(max_age <= 60, "{}", cache_control);
 
     let result: PokemonInfo = test::read_body_json(resp).await;
+    assert_eq!(result.name, "mewtwo");
 
-    assert_eq!(result, PokemonInfo {
-        name: "ditto".into(),
-        description: "'t can freely recombine its own cellular structure to transform into other life-forms.".into(),
-        is_legendary: false,
-        habitat: "urban".into(),
-    })
+    // the refresh happens in the background
+    actix_rt::time::sleep(Duration::from_millis(200)).await;
+
+    m1.assert();
+    m2.assert();
 }
 
 #[actix_rt::test]
-async fn get_pokemon_translated_not_found_mocked() {
-    let _m1 = mock("GET", "/api/v2/pokemon-species/mewthree/")
-        .with_status(404)
+async fn get_pokemon_stale_if_error_mocked() {
+    let m1 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_header("cache-control", "public, max-age=0")
+        .with_header("etag", "\"v1\"")
+        .with_body_from_file("replays/mewtwo.json")
+        .expect(1)
         .create();
 
-    let app = create_test_app(&MOCK_CONFIG).await;
+    let m2 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .match_header("if-none-match", "\"v1\"")
+        .with_status(500)
+        .expect(2)
+        .create();
 
-    let req = test::TestRequest::with_uri("/pokemon/translated/mewthree")
-        .method(Method::GET)
-        .to_request();
+    let app = create_test_app_with_staleness(Staleness {
+        while_revalidate: Duration::ZERO,
+        if_error: Duration::from_secs(60),
+    })
+    .await;
+
+    for _ in 0..2 {
+        let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
 
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
     let resp: ServiceResponse = app.call(req).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("warning").unwrap(), "111 - \"Revalidation Failed\"");
 
-    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    m1.assert();
+    m2.assert();
 }
 
 #[actix_rt::test]
-async fn get_pokemon_translated_bad_translation_mocked() {
+async fn get_pokemon_stale_if_error_expired_mocked() {
     let _m1 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .match_header("if-none-match", Matcher::Missing)
         .with_status(200)
         .with_header("content-type", "application/json")
+        .with_header("cache-control", "public, max-age=0")
+        .with_header("etag", "\"v1\"")
         .with_body_from_file("replays/mewtwo.json")
         .create();
 
-    let _m2 = mock("GET", "/translate/yoda")
-            .match_query(Matcher::UrlEncoded("text".into(), "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.".into()))
-            .with_status(500)
-            .create();
+    let _m2 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .match_header("if-none-match", "\"v1\"")
+        .with_status(500)
+        .create();
 
     let app = create_test_app(&MOCK_CONFIG).await;
 
-    let req = test::TestRequest::with_uri("/pokemon/translated/mewtwo")
-        .method(Method::GET)
-        .to_request();
-
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
     let resp: ServiceResponse = app.call(req).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::OK);
 
-    let result: PokemonInfo = test::read_body_json(resp).await;
-
-    assert_eq!(result, PokemonInfo {
-        name: "mewtwo".into(),
-        description: "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.".into(),
-        is_legendary: true,
-        habitat: "rare".into(),
-    })
+    // without a stale-if-error window, upstream errors aren't hidden
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
 }
 
 #[actix_rt::test]
-#[ignore]
-async fn get_pokemon_ok() {
-    let app = create_test_app(&APP_CONFIG).await;
+async fn post_translate_stale_if_error_mocked() {
+    let _m1 = mock("GET", "/translate/yoda")
+        .match_query(Matcher::UrlEncoded("text".into(), "Hello friend".into()))
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_header("cache-control", "max-age=0")
+        .with_header("etag", "\"t1\"")
+        .with_body(r#"{"contents":{"translated":"Friend, hello","text":"Hello friend","translation":"yoda"}}"#)
+        .create();
 
-    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
-        .method(Method::GET)
-        .to_request();
+    let _m2 = mock("GET", "/translate/yoda")
+        .match_query(Matcher::Any)
+        .match_header("if-none-match", "\"t1\"")
+        .with_status(429)
+        .create();
 
-    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+    let app = create_test_app_with_staleness(Staleness {
+        while_revalidate: Duration::ZERO,
+        if_error: Duration::from_secs(60),
+    })
+    .await;
 
-    assert_eq!(resp.status(), StatusCode::OK);
+    for _ in 0..2 {
+        let req = test::TestRequest::with_uri("/translate/yoda")
+            .method(Method::POST)
+            .set_json(&serde_json::json!({ "text": "Hello friend" }))
+            .to_request();
 
-    let result: PokemonInfo = test::read_body_json(resp).await;
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
 
-    assert_eq!(result, PokemonInfo {
-        name: "mewtwo".into(),
-        description: "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.".into(),
-        is_legendary: true,
-        habitat: "rare".into(),
-    })
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let result: TranslationInfo = test::read_body_json(resp).await;
+
+        assert_eq!(result.text, "Friend, hello");
+        assert!(result.translated);
+    }
 }
 
 #[actix_rt::test]
-#[ignore]
-async fn get_pokemon_not_found() {
-    let app = create_test_app(&APP_CONFIG).await;
+async fn record_and_replay_mocked() {
+    let dir = std::env::temp_dir().join(format!("pokefun-replay-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
 
-    let req = test::TestRequest::with_uri("/pokemon/mewthree")
-        .method(Method::GET)
-        .to_request();
+    let m = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo.json")
+        .expect(1)
+        .create();
 
-    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+    for mode in [replay::Mode::Record(dir.clone()), replay::Mode::Replay(dir.clone())] {
+        let replaying = matches!(mode, replay::Mode::Replay(_));
+        let app = TestApp {
+            upstream: UpstreamConfig {
+                replay: Some(mode),
+                ..UpstreamConfig::default()
+            },
+            ..TestApp::default()
+        }
+        .init()
+        .await;
 
-    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let result: PokemonInfo = test::read_body_json(resp).await;
+        assert_eq!(result.name, "mewtwo");
+
+        if replaying {
+            // nothing was recorded for this, so replaying it fails
+            let req = test::TestRequest::with_uri("/pokemon/mewthree").to_request();
+            let resp: ServiceResponse = app.call(req).await.expect("valid response");
+            assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    // the replay was served without making a request
+    m.assert();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A translation response that takes a second to send its body
+fn slow_translation() -> mockito::Mock {
+    // the mock server handles one request at a time, so the sleep is kept short
+    mock("GET", "/slow/translate/yoda")
+        .match_query(Matcher::Any)
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_fn(|w| {
+            std::thread::sleep(Duration::from_millis(250));
+            w.write_all(&fs::read("replays/mewtwo_yoda.json")?)
+        })
+        .create()
+}
+
+/// Config translating with [`slow_translation`], under its own path so other tests' translations aren't slowed
+fn slow_translations_config() -> AppConfig {
+    AppConfig {
+        translations_url: (mockito::server_url() + "/slow").into(),
+        ..MOCK_CONFIG.clone()
+    }
 }
 
 #[actix_rt::test]
-#[ignore]
-async fn get_pokemon_translated_legendary() {
-    let app = create_test_app(&APP_CONFIG).await;
+async fn get_pokemon_translated_deadline_mocked() {
+    let _m1 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo.json")
+        .create();
+    let _m2 = slow_translation();
 
-    let req = test::TestRequest::with_uri("/pokemon/translated/mewtwo")
-        .method(Method::GET)
-        .to_request();
+    let config = AppConfig {
+        request_deadline: Duration::from_millis(50),
+        ..slow_translations_config()
+    };
+    let app = create_test_app(&config).await;
 
+    let req = test::TestRequest::with_uri("/pokemon/translated/mewtwo").to_request();
     let resp: ServiceResponse = app.call(req).await.expect("valid response");
 
     assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "no-store");
 
     let result: PokemonInfo = test::read_body_json(resp).await;
 
-    assert_eq!(result, PokemonInfo {
-        name: "mewtwo".into(),
-        description: "Created by a scientist after years of horrific gene splicing and dna engineering experiments,  it was.".into(),
-        is_legendary: true,
-        habitat: "rare".into(),
-    })
+    assert_eq!(result.description, "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.");
 }
 
 #[actix_rt::test]
-#[ignore]
-async fn get_pokemon_translated_cave() {
-    let app = create_test_app(&APP_CONFIG).await;
-
-    let req = test::TestRequest::with_uri("/pokemon/translated/zubat")
-        .method(Method::GET)
+async fn post_translate_timeout_mocked() {
+    let _m = slow_translation();
+
+    let timeouts = UpstreamTimeouts {
+        funtranslations: Timeouts {
+            total: Duration::from_millis(50),
+            ..Timeouts::default()
+        },
+        ..UpstreamTimeouts::default()
+    };
+    let app = TestApp {
+        config: slow_translations_config(),
+        upstream: UpstreamConfig {
+            timeouts,
+            ..UpstreamConfig::default()
+        },
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+
+    let req = test::TestRequest::with_uri("/translate/yoda")
+        .method(Method::POST)
+        .set_json(&serde_json::json!({ "text": "Hello friend" }))
         .to_request();
-
     let resp: ServiceResponse = app.call(req).await.expect("valid response");
 
     assert_eq!(resp.status(), StatusCode::OK);
 
-    let result: PokemonInfo = test::read_body_json(resp).await;
+    let result: TranslationInfo = test::read_body_json(resp).await;
 
-    assert_eq!(result, PokemonInfo {
-        name: "zubat".into(),
-        description: "Forms colonies in perpetually dark places.Ultrasonic waves to identify and approach targets,  uses.".into(),
-        is_legendary: false,
-        habitat: "cave".into(),
-    })
+    assert!(!result.translated);
+    assert_eq!(result.text, "Hello friend");
 }
 
 #[actix_rt::test]
-#[ignore]
-async fn get_pokemon_translated_other() {
-    let app = create_test_app(&APP_CONFIG).await;
+async fn upstream_proxy_mocked() {
+    // the mock server stands in for the proxy, which gets the full url of each request
+    let m = mock("GET", "http://pokeapi.test/api/v2/pokemon-species/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo.json")
+        .expect(1)
+        .create();
 
-    let req = test::TestRequest::with_uri("/pokemon/translated/ditto")
-        .method(Method::GET)
-        .to_request();
+    let config = AppConfig {
+        pokemon_url: "http://pokeapi.test".into(),
+        ..MOCK_CONFIG.clone()
+    };
 
-    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+    for no_proxy in [vec![], vec!["pokeapi.test".to_owned()]] {
+        let bypassed = !no_proxy.is_empty();
+        let egress = Egress::new(EgressConfig {
+            http_proxy: Some(mockito::server_url().parse().unwrap()),
+            no_proxy,
+            ..EgressConfig::default()
+        })
+        .expect("valid egress config");
+        let app = TestApp {
+            config: config.clone(),
+            upstream: UpstreamConfig {
+                egress,
+                ..UpstreamConfig::default()
+            },
+            ..TestApp::default()
+        }
+        .init()
+        .await;
+
+        let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        // pokeapi.test doesn't resolve, so it can only be reached through the proxy
+        if bypassed {
+            assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        } else {
+            assert_eq!(resp.status(), StatusCode::OK);
+            let result: PokemonInfo = test::read_body_json(resp).await;
+            assert_eq!(result.name, "mewtwo");
+        }
+    }
 
-    assert_eq!(resp.status(), StatusCode::OK);
+    m.assert();
+}
 
-    let result: PokemonInfo = test::read_body_json(resp).await;
+/// An app requesting pokeapi from the mock server under each of `paths` in turn
+fn mirrored_app(paths: &[&str], strategy: Strategy) -> TestApp {
+    let urls: Vec<String> = paths.iter().map(|path| mockito::server_url() + path).collect();
+    TestApp {
+        config: AppConfig {
+            pokemon_url: urls[0].clone().into(),
+            ..MOCK_CONFIG.clone()
+        },
+        upstream: UpstreamConfig {
+            mirrors: Mirrors::from(Live::new(Current {
+                mirrors: Groups::new(MirrorsConfig {
+                    pokeapi: urls,
+                    strategy,
+                    cooldown: Duration::from_secs(60),
+                    ..MirrorsConfig::default()
+                }),
+                ..Current::default()
+            })),
+            ..UpstreamConfig::default()
+        },
+        ..TestApp::default()
+    }
+}
 
-    assert_eq!(result, PokemonInfo {
-        name: "ditto".into(),
-        description: "'t can freely recombine its own cellular structure to transform into other life-forms.".into(),
-        is_legendary: false,
-        habitat: "urban".into(),
-    })
+fn mirrored_species(path: &str, status: usize, expect: usize) -> Mock {
+    mock("GET", format!("{}/api/v2/pokemon-species/mewtwo/", path).as_str())
+        .with_status(status)
+        .with_header("content-type", "application/json")
+        // not cached, so every request reaches the mirrors
+        .with_header("cache-control", "no-store")
+        .with_body_from_file("replays/mewtwo.json")
+        .expect(expect)
+        .create()
 }
 
 #[actix_rt::test]
-#[ignore]
-async fn get_pokemon_translated_not_found() {
-    let app = create_test_app(&APP_CONFIG).await;
+async fn get_pokemon_mirror_failover_mocked() {
+    let primary = mirrored_species("/primary", 503, 1);
+    let backup = mirrored_species("/backup", 200, 2);
 
-    let req = test::TestRequest::with_uri("/pokemon/translated/mewthree")
-        .method(Method::GET)
-        .to_request();
+    let app = mirrored_app(&["/primary", "/backup"], Strategy::Priority).init().await;
 
-    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+    // the second request skips the primary while it cools down
+    for _ in 0..2 {
+        let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
 
-    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let result: PokemonInfo = test::read_body_json(resp).await;
+        assert_eq!(result.name, "mewtwo");
+    }
+
+    primary.assert();
+    backup.assert();
+}
+
+#[actix_rt::test]
+async fn get_pokemon_mirrors_down_mocked() {
+    let first = mirrored_species("/down-first", 503, 2);
+    let second = mirrored_species("/down-second", 503, 1);
+
+    let app = mirrored_app(&["/down-first", "/down-second"], Strategy::Priority).init().await;
+
+    // once every mirror is cooling down, only the first is tried
+    for _ in 0..2 {
+        let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    first.assert();
+    second.assert();
+}
+
+#[actix_rt::test]
+async fn get_pokemon_mirror_round_robin_mocked() {
+    let first = mirrored_species("/first", 200, 1);
+    let second = mirrored_species("/second", 200, 1);
+
+    let app = mirrored_app(&["/first", "/second"], Strategy::RoundRobin).init().await;
+
+    for _ in 0..2 {
+        let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    first.assert();
+    second.assert();
+}
+
+/// An app with the pokeapi `timeouts`, failing over between `mirrors` if there are any
+fn timed_app(config: AppConfig, timeouts: Timeouts, mirrors: Vec<String>) -> TestApp {
+    TestApp {
+        config,
+        upstream: UpstreamConfig {
+            timeouts: UpstreamTimeouts {
+                pokeapi: timeouts,
+                ..UpstreamTimeouts::default()
+            },
+            mirrors: Mirrors::from(Live::new(Current {
+                mirrors: Groups::new(MirrorsConfig {
+                    pokeapi: mirrors,
+                    ..MirrorsConfig::default()
+                }),
+                ..Current::default()
+            })),
+            ..UpstreamConfig::default()
+        },
+        ..TestApp::default()
+    }
+}
+
+#[actix_rt::test]
+async fn get_pokemon_slow_body_mocked() {
+    let (upstream, config) = fake_upstream(Fake {
+        body_latency: Duration::from_millis(300),
+        ..fixtures()
+    });
+
+    // the read timeout only waits for the headers, the total timeout waits for the body too
+    let cases = [
+        (Timeouts {
+            connect: Duration::from_millis(50),
+            read: Duration::from_millis(50),
+            ..Timeouts::default()
+        }, StatusCode::OK),
+        (Timeouts {
+            total: Duration::from_millis(100),
+            ..Timeouts::default()
+        }, StatusCode::INTERNAL_SERVER_ERROR),
+    ];
+    for (timeouts, status) in cases {
+        let app = timed_app(config.clone(), timeouts, Vec::new()).init().await;
+
+        let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        assert_eq!(resp.status(), status, "{:?}", timeouts);
+    }
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_mirrors_total_timeout_mocked() {
+    let (failing, config) = fake_upstream(Fake {
+        latency: Duration::from_millis(200),
+        error_rate: 1.0,
+        ..fixtures()
+    });
+    let (slow, backup) = fake_upstream(Fake {
+        latency: Duration::from_millis(200),
+        ..fixtures()
+    });
+
+    let timeouts = Timeouts {
+        total: Duration::from_millis(300),
+        ..Timeouts::default()
+    };
+    let mirrors = vec![config.pokemon_url.to_string(), backup.pokemon_url.to_string()];
+    let app = timed_app(config, timeouts, mirrors).init().await;
+
+    // failing over only gets what's left of the total timeout, not another 300ms
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    failing.stop(false).await;
+    slow.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_translated_legendary_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/mewtwo")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "public, max-age=300");
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result, PokemonInfo {
+        name: "mewtwo".into(),
+        description: "Created by a scientist after years of horrific gene splicing and dna engineering experiments,  it was.".into(),
+        is_legendary: true,
+        habitat: "rare".into(),
+    });
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_translated_cave_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/zubat")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result, PokemonInfo {
+        name: "zubat".into(),
+        description: "Forms colonies in perpetually dark places.Ultrasonic waves to identify and approach targets,  uses.".into(),
+        is_legendary: false,
+        habitat: "cave".into(),
+    });
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_translated_other_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/ditto")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result, PokemonInfo {
+        name: "ditto".into(),
+        description: "'t can freely recombine its own cellular structure to transform into other life-forms.".into(),
+        is_legendary: false,
+        habitat: "urban".into(),
+    });
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_null_habitat_mocked() {
+    let (upstream, config) = fake_upstream(Fake {
+        null_habitat: std::iter::once("ditto".to_owned()).collect(),
+        ..fixtures()
+    });
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/ditto")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result.name, "ditto");
+    assert_eq!(result.habitat, "");
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_translated_quota_exceeded_mocked() {
+    let (upstream, config) = fake_upstream(Fake {
+        quota: Some(Quota::new(0, Duration::from_secs(3600))),
+        ..fixtures()
+    });
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/mewtwo")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    // funtranslations answering 429 falls back to the original description, uncached
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "no-store");
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result.description, "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.");
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_upstream_error_mocked() {
+    let (upstream, config) = fake_upstream(Fake {
+        error_rate: 1.0,
+        ..fixtures()
+    });
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_translated_not_found_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/mewthree")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_translated_bad_translation_mocked() {
+    let _m1 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo.json")
+        .create();
+
+    let _m2 = mock("GET", "/translate/yoda")
+            .match_query(Matcher::UrlEncoded("text".into(), "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.".into()))
+            .with_status(500)
+            .create();
+
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/mewtwo")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "no-store");
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result, PokemonInfo {
+        name: "mewtwo".into(),
+        description: "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.".into(),
+        is_legendary: true,
+        habitat: "rare".into(),
+    })
+}
+
+#[actix_rt::test]
+async fn get_pokemon_evolutions_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/zubat/evolutions")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: Evolution = test::read_body_json(resp).await;
+
+    assert_eq!(result, Evolution {
+        name: "zubat".into(),
+        description: None,
+        triggers: vec![],
+        evolves_to: vec![Evolution {
+            name: "golbat".into(),
+            description: None,
+            triggers: vec![EvolutionTrigger {
+                trigger: "level-up".into(),
+                conditions: vec![("min_level".into(), 22.into())].into_iter().collect(),
+            }],
+            evolves_to: vec![Evolution {
+                name: "crobat".into(),
+                description: None,
+                triggers: vec![EvolutionTrigger {
+                    trigger: "level-up".into(),
+                    conditions: vec![("min_happiness".into(), 160.into())].into_iter().collect(),
+                }],
+                evolves_to: vec![],
+            }],
+        }],
+    });
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_evolutions_translated_mocked() {
+    let m1 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        // not cached, so looking it up again would reach pokeapi
+        .with_header("cache-control", "no-store")
+        .with_body_from_file("replays/mewtwo.json")
+        .expect(1)
+        .create();
+
+    let _m2 = mock("GET", "/api/v2/evolution-chain/77/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo_evolutions.json")
+        .create();
+
+    let _m3 = mock("GET", "/translate/yoda")
+        .match_query(Matcher::UrlEncoded("text".into(), "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.".into()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo_yoda.json")
+        .create();
+
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo/evolutions?translated=true")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: Evolution = test::read_body_json(resp).await;
+
+    assert_eq!(result, Evolution {
+        name: "mewtwo".into(),
+        description: Some("Created by a scientist after years of horrific gene splicing and dna engineering experiments,  it was.".into()),
+        triggers: vec![],
+        evolves_to: vec![],
+    });
+    m1.assert();
+}
+
+#[actix_rt::test]
+async fn get_pokemon_evolutions_not_found_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewthree/evolutions")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_stats_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo/stats")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: PokemonStats = test::read_body_json(resp).await;
+
+    assert_eq!(result, PokemonStats {
+        name: "mewtwo".into(),
+        types: vec!["psychic".into()],
+        abilities: vec![
+            Ability { name: "pressure".into(), is_hidden: false },
+            Ability { name: "unnerve".into(), is_hidden: true },
+        ],
+        base_stats: vec![
+            ("hp".into(), 106),
+            ("attack".into(), 110),
+            ("defense".into(), 90),
+            ("special-attack".into(), 154),
+            ("special-defense".into(), 90),
+            ("speed".into(), 130),
+        ].into_iter().collect(),
+        height: 20,
+        weight: 1220,
+        sprite: Some("https://raw.githubusercontent.com/PokeAPI/sprites/master/sprites/pokemon/150.png".into()),
+    });
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_stats_not_found_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewthree/stats")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_pokemon_stats_cached_mocked() {
+    let m = mock("GET", "/stats-cached/api/v2/pokemon/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_header("cache-control", "public, max-age=86400")
+        .with_body_from_file("replays/mewtwo_pokemon.json")
+        .expect(1)
+        .create();
+
+    let config = AppConfig {
+        pokemon_url: (mockito::server_url() + "/stats-cached").into(),
+        ..MOCK_CONFIG.clone()
+    };
+    let app = create_test_app(&config).await;
+
+    // names are cached in lowercase, so both are served by the one lookup
+    for name in ["Mewtwo", "mewtwo"] {
+        let req = test::TestRequest::with_uri(&format!("/pokemon/{}/stats", name))
+            .method(Method::GET)
+            .to_request();
+
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().contains_key("etag"));
+
+        let result: PokemonStats = test::read_body_json(resp).await;
+
+        assert_eq!(result.name, "mewtwo");
+    }
+
+    m.assert();
+}
+
+#[actix_rt::test]
+async fn get_habitats_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/habitats")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: Vec<String> = test::read_body_json(resp).await;
+
+    assert_eq!(
+        result,
+        [
+            "cave",
+            "forest",
+            "grassland",
+            "mountain",
+            "rare",
+            "rough-terrain",
+            "sea",
+            "urban",
+            "waters-edge"
+        ]
+    );
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_habitat_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/habitats/cave")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: HabitatInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result.name, "cave");
+    assert_eq!(result.species.len(), 10);
+    assert_eq!(result.species[0].name, "zubat");
+    assert!(result.species.iter().all(|s| s.description.is_none()));
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_habitat_translated_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/habitats/cave?translated=true")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: HabitatInfo = test::read_body_json(resp).await;
+
+    // only zubat's species has a fixture, the others fail to look up and are left undescribed
+    assert_eq!(result.species[0].description.as_deref(), Some("Forms colonies in perpetually dark places.Ultrasonic waves to identify and approach targets,  uses."));
+    assert!(result.species[1..].iter().all(|s| s.description.is_none()));
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_habitat_not_found_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/habitats/volcano")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_random_pokemon_legendary_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/random?legendary=true")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result.name, "mewtwo");
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_random_pokemon_translated_legendary_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/random?legendary=true")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result, PokemonInfo {
+        name: "mewtwo".into(),
+        description: "Created by a scientist after years of horrific gene splicing and dna engineering experiments,  it was.".into(),
+        is_legendary: true,
+        habitat: "rare".into(),
+    });
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_random_pokemon_habitat_generation_mocked() {
+    let m1 = mock("GET", "/api/v2/pokemon-habitat/cave/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/cave_habitat.json")
+        .create();
+
+    let m2 = mock("GET", "/api/v2/generation/generation-i/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/generation_i.json")
+        .create();
+
+    // every cave species looks like zubat
+    let species = Matcher::Regex("^/api/v2/pokemon-species/[a-z-]+/$".into());
+    let _m3 = mock("GET", species)
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/zubat.json")
+        .create();
+
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/random?habitat=cave&generation=generation-i")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result.habitat, "cave");
+    m1.assert();
+    m2.assert();
+}
+
+#[actix_rt::test]
+async fn get_random_pokemon_unknown_habitat_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/random?habitat=volcano")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_random_pokemon_legendary_gave_up_mocked() {
+    // under their own path, so the other tests' species aren't answered by these
+    let _m1 = mock("GET", "/gave-up/api/v2/generation/generation-i/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/generation_i.json")
+        .create();
+
+    // every species looks like zubat, so there's no legendary to find
+    let species = Matcher::Regex("^/gave-up/api/v2/pokemon-species/[a-z-]+/$".into());
+    let _m2 = mock("GET", species)
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/zubat.json")
+        .create();
+
+    let config = AppConfig {
+        pokemon_url: (mockito::server_url() + "/gave-up").into(),
+        ..MOCK_CONFIG.clone()
+    };
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/random?legendary=true&generation=generation-i")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    // there are too many species to look up them all, so there may be a legendary it missed
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_rt::test]
+async fn get_daily_pokemon_deterministic_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let mut picks = vec![];
+    for _ in 0..4 {
+        let req = test::TestRequest::with_uri("/pokemon/daily")
+            .method(Method::GET)
+            .to_request();
+
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let result: PokemonInfo = test::read_body_json(resp).await;
+        picks.push(result.name);
+    }
+
+    assert!(picks.iter().all(|name| *name == picks[0]));
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn get_daily_pokemon_cached_mocked() {
+    let list = mock("GET", "/daily-cached/api/v2/pokemon-species/")
+        .match_query(Matcher::Any)
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/species_list.json")
+        .expect(1)
+        .create();
+
+    // without the legendary filter, only the picked species is looked up
+    let species = Matcher::Regex("^/daily-cached/api/v2/pokemon-species/[a-z-]+/$".into());
+    let species = mock("GET", species)
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/zubat.json")
+        .expect(1)
+        .create();
+
+    let config = AppConfig {
+        pokemon_url: (mockito::server_url() + "/daily-cached").into(),
+        ..MOCK_CONFIG.clone()
+    };
+    let app = create_test_app(&config).await;
+
+    for _ in 0..2 {
+        let req = test::TestRequest::with_uri("/pokemon/daily")
+            .method(Method::GET)
+            .to_request();
+
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    list.assert();
+    species.assert();
+}
+
+#[actix_rt::test]
+async fn post_translate_mocked() {
+    let (upstream, config) = fake_upstream(fixtures());
+
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/translate/yoda")
+        .method(Method::POST)
+        .set_json(&serde_json::json!({
+            "text": "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.",
+        }))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: TranslationInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result, TranslationInfo {
+        text: "Created by a scientist after years of horrific gene splicing and dna engineering experiments,  it was.".into(),
+        translated: true,
+        original: "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.".into(),
+        engine: "yoda".into(),
+    });
+
+    upstream.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn post_translate_rate_limited_mocked() {
+    // under its own path, so the other tests' translations aren't answered by it
+    let _m = mock("GET", "/rate-limited/translate/yoda")
+        .match_query(Matcher::Any)
+        .with_status(429)
+        .create();
+
+    let config = AppConfig {
+        translations_url: (mockito::server_url() + "/rate-limited").into(),
+        ..MOCK_CONFIG.clone()
+    };
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/translate/yoda")
+        .method(Method::POST)
+        .set_json(&serde_json::json!({ "text": "Hello friend" }))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: TranslationInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result, TranslationInfo {
+        text: "Hello friend".into(),
+        translated: false,
+        original: "Hello friend".into(),
+        engine: "yoda".into(),
+    })
+}
+
+#[actix_rt::test]
+async fn post_translate_unknown_engine() {
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    let req = test::TestRequest::with_uri("/translate/pirate")
+        .method(Method::POST)
+        .set_json(&serde_json::json!({ "text": "Hello friend" }))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn post_translate_too_long() {
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    let req = test::TestRequest::with_uri("/translate/yoda")
+        .method(Method::POST)
+        .set_json(&serde_json::json!({ "text": "a".repeat(1001) }))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[actix_rt::test]
+async fn get_metrics_mocked() {
+    let _m1 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo.json")
+        .create();
+
+    let _m2 = mock("GET", "/translate/yoda")
+        .match_query(Matcher::Any)
+        .with_status(429)
+        .create();
+
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/mewtwo")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::with_uri("/metrics")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body = test::read_body(resp).await;
+    let body = std::str::from_utf8(&body).expect("metrics should be utf8");
+
+    // other tests share the metrics registry, so only check the series exist
+    for series in [
+        r#"http_requests_total{method="GET",route="/pokemon/translated/{pokemon_name}",status="200"}"#,
+        r#"upstream_requests_total{status="200",upstream="pokeapi"}"#,
+        r#"upstream_requests_total{status="429",upstream="funtranslations"}"#,
+        r#"translation_fallbacks_total{reason="rate_limited"}"#,
+    ] {
+        assert!(body.contains(series), "{} missing from\n{}", series, body);
+    }
+}
+
+#[actix_rt::test]
+async fn traceparent_propagated_mocked() {
+    // the upstream request must continue the trace from the incoming request
+    let _m = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .match_header(
+            "traceparent",
+            Matcher::Regex("^00-0af7651916cd43dd8448eb211c80319c-[0-9a-f]{16}-01$".into()),
+        )
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo.json")
+        .create();
+
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::GET)
+        .insert_header((
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        ))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn request_id_propagated() {
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    let req = test::TestRequest::with_uri("/healthz")
+        .method(Method::GET)
+        .insert_header(("x-request-id", "abc-123"))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("x-request-id").unwrap(), "abc-123");
+}
+
+#[actix_rt::test]
+async fn request_id_generated() {
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    let req = test::TestRequest::with_uri("/healthz")
+        .method(Method::GET)
+        .insert_header(("x-request-id", "not a valid id"))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    let request_id = resp.headers().get("x-request-id").unwrap();
+    assert_ne!(request_id, "not a valid id");
+    assert_eq!(request_id.len(), 36);
+}
+
+#[actix_rt::test]
+async fn otlp_export_mocked() {
+    let m = mock("POST", "/v1/traces")
+        .match_header("content-type", "application/x-protobuf")
+        .with_status(200)
+        .create();
+
+    let tracer = telemetry::init_tracer(&mockito::server_url()).expect("tracer should install");
+    tracer.in_span("test span", |_| {});
+
+    // flushes the pending span to the collector
+    global::shutdown_tracer_provider();
+
+    m.assert();
+}
+
+#[actix_rt::test]
+async fn get_healthz() {
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    let req = test::TestRequest::with_uri("/healthz")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn get_readyz_strict_mocked() {
+    let _m1 = mock("GET", "/api/v2/").with_status(200).create();
+    let _m2 = mock("GET", "/").with_status(404).create();
+
+    let config = AppConfig {
+        readiness: Readiness::Strict,
+        ..MOCK_CONFIG.clone()
+    };
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/readyz")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: serde_json::Value = test::read_body_json(resp).await;
+
+    assert_eq!(
+        result,
+        serde_json::json!({
+            "ready": true,
+            "upstreams": { "pokeapi": true, "funtranslations": true },
+        })
+    );
+}
+
+#[actix_rt::test]
+async fn get_readyz_strict_unreachable_mocked() {
+    let _m1 = mock("GET", "/api/v2/").with_status(503).create();
+    let _m2 = mock("GET", "/").with_status(404).create();
+
+    let config = AppConfig {
+        readiness: Readiness::Strict,
+        ..MOCK_CONFIG.clone()
+    };
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/readyz")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_rt::test]
+async fn get_readyz_report_unreachable_mocked() {
+    let _m1 = mock("GET", "/api/v2/").with_status(503).create();
+    let _m2 = mock("GET", "/").with_status(404).create();
+
+    let config = AppConfig {
+        readiness: Readiness::Report,
+        ..MOCK_CONFIG.clone()
+    };
+    let app = create_test_app(&config).await;
+
+    let req = test::TestRequest::with_uri("/readyz")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: serde_json::Value = test::read_body_json(resp).await;
+
+    assert_eq!(
+        result,
+        serde_json::json!({
+            "ready": true,
+            "upstreams": { "pokeapi": false, "funtranslations": true },
+        })
+    );
+}
+
+#[actix_rt::test]
+async fn get_readyz_cached_mocked() {
+    // under their own path, so only this test's probes are counted
+    let m1 = mock("GET", "/ready-cached/api/v2/").with_status(200).expect(1).create();
+    let m2 = mock("GET", "/ready-cached/").with_status(404).expect(1).create();
+
+    let url = mockito::server_url() + "/ready-cached";
+    let config = AppConfig {
+        pokemon_url: url.clone().into(),
+        translations_url: url.into(),
+        readiness: Readiness::Strict,
+        ..MOCK_CONFIG.clone()
+    };
+    let app = create_test_app(&config).await;
+
+    // the second probe is answered from the first's check
+    for _ in 0..2 {
+        let req = test::TestRequest::with_uri("/readyz")
+            .method(Method::GET)
+            .to_request();
+
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    m1.assert();
+    m2.assert();
+}
+
+#[actix_rt::test]
+async fn get_readyz_draining() {
+    let draining = Draining::default();
+    let app = TestApp {
+        draining: draining.clone(),
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+
+    draining.start();
+
+    let req = test::TestRequest::with_uri("/readyz")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let result: serde_json::Value = test::read_body_json(resp).await;
+
+    assert_eq!(
+        result,
+        serde_json::json!({ "ready": false, "draining": true })
+    );
+}
+
+#[actix_rt::test]
+async fn get_version() {
+    let app = create_test_app(&MOCK_CONFIG).await;
+
+    let req = test::TestRequest::with_uri("/version")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: serde_json::Value = test::read_body_json(resp).await;
+
+    assert_eq!(result["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(result["upstreams"]["pokeapi"], mockito::server_url());
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn get_pokemon_ok() {
+    let app = create_live_test_app().await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result, PokemonInfo {
+        name: "mewtwo".into(),
+        description: "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.".into(),
+        is_legendary: true,
+        habitat: "rare".into(),
+    })
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn get_pokemon_not_found() {
+    let app = create_live_test_app().await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewthree")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn get_pokemon_translated_legendary() {
+    let app = create_live_test_app().await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/mewtwo")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result, PokemonInfo {
+        name: "mewtwo".into(),
+        description: "Created by a scientist after years of horrific gene splicing and dna engineering experiments,  it was.".into(),
+        is_legendary: true,
+        habitat: "rare".into(),
+    })
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn get_pokemon_translated_cave() {
+    let app = create_live_test_app().await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/zubat")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result, PokemonInfo {
+        name: "zubat".into(),
+        description: "Forms colonies in perpetually dark places.Ultrasonic waves to identify and approach targets,  uses.".into(),
+        is_legendary: false,
+        habitat: "cave".into(),
+    })
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn get_pokemon_translated_other() {
+    let app = create_live_test_app().await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/ditto")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: PokemonInfo = test::read_body_json(resp).await;
+
+    assert_eq!(result, PokemonInfo {
+        name: "ditto".into(),
+        description: "'t can freely recombine its own cellular structure to transform into other life-forms.".into(),
+        is_legendary: false,
+        habitat: "urban".into(),
+    })
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn get_pokemon_translated_not_found() {
+    let app = create_live_test_app().await;
+
+    let req = test::TestRequest::with_uri("/pokemon/translated/mewthree")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+/// An API keys file written for a test, deleted when dropped
+struct KeysFile(PathBuf);
+
+impl Drop for KeysFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Write an API keys file for a test, with a `secret` key labelled `full`,
+/// a `translator` key only allowed to use the translated endpoints
+/// and a `pokedex` key only allowed to use the untranslated ones
+fn write_api_keys(name: &str) -> KeysFile {
+    let path = std::env::temp_dir().join(format!("pokefun-{}-{}.json", name, std::process::id()));
+    fs::write(
+        &path,
+        r#"[
+            {
+                "sha256": "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b",
+                "label": "full"
+            },
+            {
+                "sha256": "8935b10f056ecd093704fddf6ff2928b644950851008b6dc1f82e0f664ebda99",
+                "label": "translator",
+                "scopes": ["translated"]
+            },
+            {
+                "sha256": "f829233ddb69db70deac41188f27c8eeb50971fe642de8861afabd41c39bc5dc",
+                "label": "pokedex",
+                "scopes": ["pokemon"]
+            }
+        ]"#,
+    )
+    .expect("api keys file written");
+    KeysFile(path)
+}
+
+/// Authentication with the keys in `keys`
+fn load_api_keys(keys: &KeysFile) -> ApiKeys {
+    let keys = Keys::read(&keys.0).expect("valid api keys");
+    ApiKeys::from(Live::new(Current {
+        api_keys: Some(keys),
+        ..Current::default()
+    }))
+}
+
+#[actix_rt::test]
+async fn api_key_missing() {
+    let api_keys = load_api_keys(&write_api_keys("missing"));
+    let app = TestApp {
+        api_keys,
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.headers().get("www-authenticate").unwrap(), "Bearer");
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::GET)
+        .insert_header(("x-api-key", "wrong"))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn api_key_bearer_mocked() {
+    let _m = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo.json")
+        .create();
+
+    let api_keys = load_api_keys(&write_api_keys("bearer"));
+    let app = TestApp {
+        api_keys,
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::GET)
+        .insert_header(("authorization", "Bearer secret"))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    // shared caches mustn't serve it to clients without a key
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "private, max-age=3600");
+    assert_eq!(resp.headers().get("vary").unwrap(), "Authorization, X-Api-Key");
+}
+
+#[actix_rt::test]
+async fn api_key_scopes_mocked() {
+    let _m = mock("GET", "/translate/yoda")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo_yoda.json")
+        .create();
+
+    let api_keys = load_api_keys(&write_api_keys("scopes"));
+    let app = TestApp {
+        api_keys,
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::GET)
+        .insert_header(("x-api-key", "translator"))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let req = test::TestRequest::with_uri("/translate/yoda")
+        .method(Method::POST)
+        .insert_header(("x-api-key", "translator"))
+        .set_json(&serde_json::json!({ "text": "hello" }))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // the query is decoded before checking the scope, so encoding it doesn't get around it
+    for query in ["translated=true", "translated=%74rue", "%74ranslated=true"] {
+        let req = test::TestRequest::with_uri(&format!("/habitats/cave?{}", query))
+            .method(Method::GET)
+            .insert_header(("x-api-key", "pokedex"))
+            .to_request();
+
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", query);
+    }
+}
+
+#[actix_rt::test]
+async fn api_key_not_needed_for_probes() {
+    let api_keys = load_api_keys(&write_api_keys("probes"));
+    let app = TestApp {
+        api_keys,
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+
+    let req = test::TestRequest::with_uri("/healthz")
+        .method(Method::GET)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn api_keys_reload_mocked() {
+    let _m = mock("GET", "/translate/yoda")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo_yoda.json")
+        .create();
+
+    let keys = write_api_keys("reload");
+    let path = &keys.0;
+    let config = || Config::from_iter(&["pokefun", "--api-keys-file", path.to_str().unwrap()]);
+    let live = Live::new(Current::load(&config()).expect("valid api keys"));
+    let reloader = Reloader::new(&config(), live.clone(), false).expect("valid config");
+    let app = TestApp {
+        api_keys: live.into(),
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+
+    fs::write(
+        path,
+        r#"[{
+            "sha256": "f42546d5ecdd452509808b2d6d0413b5a738c70a793b99ccf8ed6f423aac83d3",
+            "label": "rotated"
+        }]"#,
+    )
+    .expect("api keys file written");
+    reloader.apply(config()).expect("valid api keys");
+
+    let req = test::TestRequest::with_uri("/translate/yoda")
+        .method(Method::POST)
+        .insert_header(("x-api-key", "secret"))
+        .set_json(&serde_json::json!({ "text": "hello" }))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // a broken file keeps the current keys
+    fs::write(path, "not json").expect("api keys file written");
+    assert!(reloader.apply(config()).is_err());
+
+    let req = test::TestRequest::with_uri("/translate/yoda")
+        .method(Method::POST)
+        .insert_header(("x-api-key", "rotated"))
+        .set_json(&serde_json::json!({ "text": "hello" }))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+/// A rate limiter with `limits`, trusting `trusted_proxies` to set `X-Forwarded-For`
+fn rate_limiter(limits: Limits, trusted_proxies: Vec<IpAddr>) -> RateLimiter {
+    RateLimiter::from(Live::new(Current {
+        limits,
+        trusted_proxies,
+        ..Current::default()
+    }))
+}
+
+#[actix_rt::test]
+async fn rate_limit_mocked() {
+    let _m = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo.json")
+        .create();
+
+    let limits = Limits {
+        pokemon: Some(2),
+        translated: None,
+    };
+    let app = TestApp {
+        rate_limiter: rate_limiter(limits, vec![]),
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+    let peer: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+
+    for remaining in ["1", "0"] {
+        let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+            .method(Method::GET)
+            .peer_addr(peer)
+            .to_request();
+
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "2");
+        assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), remaining);
+    }
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::GET)
+        .peer_addr(peer)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "0");
+    let retry_after = resp.headers().get("retry-after").unwrap();
+    let retry_after: u64 = retry_after.to_str().unwrap().parse().unwrap();
+    assert!(retry_after > 0 && retry_after <= 60);
+
+    // every API endpoint shares the limit
+    let req = test::TestRequest::with_uri("/habitats")
+        .method(Method::GET)
+        .peer_addr(peer)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // health probes aren't limited
+    let req = test::TestRequest::with_uri("/healthz")
+        .method(Method::GET)
+        .peer_addr(peer)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn reload_rate_limit_mocked() {
+    let _m = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo.json")
+        .create();
+
+    let config = Config::from_iter(&["pokefun", "--rate-limit=1"]);
+    let live = Live::new(Current::load(&config).expect("valid config"));
+    let reloader = Reloader::new(&config, live.clone(), false).expect("valid config");
+    let app = TestApp {
+        rate_limiter: live.into(),
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+    let peer: SocketAddr = "10.0.0.2:1234".parse().unwrap();
+    let get = || test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::GET)
+        .peer_addr(peer)
+        .to_request();
+
+    let resp: ServiceResponse = app.call(get()).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp: ServiceResponse = app.call(get()).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // the running app picks up the new limit, still counting the requests already made
+    let changes = reloader
+        .apply(Config::from_iter(&["pokefun", "--rate-limit=3"]))
+        .expect("valid config");
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].to_string(), "rate_limit: 1 -> 3");
+
+    let resp: ServiceResponse = app.call(get()).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "3");
+    assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "0");
+}
+
+#[actix_rt::test]
+async fn rate_limit_forwarded_mocked() {
+    let _m = mock("GET", "/translate/yoda")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo_yoda.json")
+        .create();
+
+    let limits = Limits {
+        pokemon: None,
+        translated: Some(1),
+    };
+    let proxy = "10.0.0.1".parse().unwrap();
+    let app = TestApp {
+        rate_limiter: rate_limiter(limits, vec![proxy]),
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+
+    let requests = [
+        ("10.0.0.1:1234", "1.1.1.1", StatusCode::OK),
+        ("10.0.0.1:1234", "2.2.2.2, 10.0.0.1", StatusCode::OK),
+        ("10.0.0.1:1234", "1.1.1.1", StatusCode::TOO_MANY_REQUESTS),
+        // the header can't be spoofed by clients that aren't trusted proxies
+        ("3.3.3.3:1234", "4.4.4.4", StatusCode::OK),
+        ("3.3.3.3:1234", "5.5.5.5", StatusCode::TOO_MANY_REQUESTS),
+        // addresses after one that can't be parsed are still used
+        ("10.0.0.1:1234", "6.6.6.6, bogus, 7.7.7.7", StatusCode::OK),
+        ("10.0.0.1:1234", "bogus, 7.7.7.7", StatusCode::TOO_MANY_REQUESTS),
+        // but addresses before it aren't trusted, leaving the proxy's own address
+        ("10.0.0.1:1234", "8.8.8.8, bogus", StatusCode::OK),
+        ("10.0.0.1:1234", "9.9.9.9, bogus, 10.0.0.1", StatusCode::TOO_MANY_REQUESTS),
+    ];
+
+    for (peer, forwarded_for, status) in requests {
+        let req = test::TestRequest::with_uri("/translate/yoda")
+            .method(Method::POST)
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("x-forwarded-for", forwarded_for))
+            .set_json(&serde_json::json!({ "text": "hello" }))
+            .to_request();
+
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        assert_eq!(resp.status(), status, "{} via {}", forwarded_for, peer);
+    }
+}
+
+#[actix_rt::test]
+async fn rate_limit_translations_mocked() {
+    let _m = mock("GET", "/api/v2/pokemon-habitat/cave/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/cave_habitat.json")
+        .create();
+
+    let limits = Limits {
+        pokemon: None,
+        translated: Some(12),
+    };
+    let app = TestApp {
+        rate_limiter: rate_limiter(limits, vec![]),
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+    let peer: SocketAddr = "10.0.0.3:1234".parse().unwrap();
+    let get = || test::TestRequest::with_uri("/habitats/cave?translated=true")
+        .method(Method::GET)
+        .peer_addr(peer)
+        .to_request();
+
+    // each of the 10 species in the cave is charged as a translation
+    let resp: ServiceResponse = app.call(get()).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "2");
+
+    let resp: ServiceResponse = app.call(get()).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "0");
+    assert!(resp.headers().contains_key("retry-after"));
+}
+
+#[actix_rt::test]
+async fn get_habitat_translations_capped_mocked() {
+    let species: Vec<_> = (0..25)
+        .map(|i| serde_json::json!({ "name": format!("grassmon-{}", i), "url": "" }))
+        .collect();
+    let _m = mock("GET", "/api/v2/pokemon-habitat/grassland/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(serde_json::json!({ "name": "grassland", "pokemon_species": species }).to_string())
+        .create();
+    let lookups = mock("GET", Matcher::Regex("^/api/v2/pokemon-species/grassmon-".into()))
+        .with_status(404)
+        .expect(20)
+        .create();
+
+    let limits = Limits {
+        pokemon: None,
+        translated: Some(100),
+    };
+    let app = TestApp {
+        rate_limiter: rate_limiter(limits, vec![]),
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+
+    let req = test::TestRequest::with_uri("/habitats/grassland?translated=true")
+        .method(Method::GET)
+        .peer_addr("10.0.0.4:1234".parse().unwrap())
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    // only the first 20 species are looked up and charged for
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "80");
+    let result: HabitatInfo = test::read_body_json(resp).await;
+    assert_eq!(result.species.len(), 25);
+    lookups.assert();
+}
+
+fn cors_config() -> AppConfig {
+    AppConfig {
+        cors: Some(CorsConfig {
+            allowed_origins: vec!["https://example.com".into(), "https://*.example.com".into()].into(),
+            allowed_methods: vec![Method::GET, Method::POST],
+            allowed_headers: vec![HeaderName::from_static("x-api-key")],
+            max_age: Some(600),
+        }),
+        ..MOCK_CONFIG.clone()
+    }
+}
+
+#[actix_rt::test]
+async fn cors_preflight() {
+    let app = create_test_app(&cors_config()).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::OPTIONS)
+        .insert_header(("origin", "https://app.example.com"))
+        .insert_header(("access-control-request-method", "GET"))
+        .insert_header(("access-control-request-headers", "x-api-key"))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    let headers = resp.headers();
+    assert_eq!(
+        headers.get("access-control-allow-origin").unwrap(),
+        "https://app.example.com"
+    );
+    assert_eq!(headers.get("access-control-max-age").unwrap(), "600");
+    assert!(headers.contains_key("access-control-allow-methods"));
+    assert!(headers.contains_key("access-control-allow-headers"));
+}
+
+#[actix_rt::test]
+async fn cors_request_mocked() {
+    let _m = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo.json")
+        .create();
+
+    let app = create_test_app(&cors_config()).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::GET)
+        .insert_header(("origin", "https://example.com"))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    let headers = resp.headers();
+    assert_eq!(
+        headers.get("access-control-allow-origin").unwrap(),
+        "https://example.com"
+    );
+    let exposed = headers
+        .get("access-control-expose-headers")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(exposed.contains("x-request-id"));
+}
+
+#[actix_rt::test]
+async fn cors_origin_not_allowed() {
+    let app = create_test_app(&cors_config()).await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo")
+        .method(Method::OPTIONS)
+        .insert_header(("origin", "https://example.org"))
+        .insert_header(("access-control-request-method", "GET"))
+        .to_request();
+
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(!resp
+        .headers()
+        .contains_key("access-control-allow-origin"));
+}
+
+#[actix_rt::test]
+async fn https_redirect() {
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(HttpsPort(8443)))
+            .default_service(web::to(tls::redirect)),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/pokemon/mewtwo?x=1")
+        .method(Method::GET)
+        .insert_header(("host", "example.com:8080"))
+        .to_request();
+
+    let resp = app.call(req).await.expect("valid response");
+
+    assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        resp.headers().get("location").unwrap(),
+        "https://example.com:8443/pokemon/mewtwo?x=1"
+    );
+}
+
+#[cfg(feature = "chaos")]
+#[actix_rt::test]
+async fn chaos_mocked() {
+    let _m1 = mock("GET", "/api/v2/pokemon-species/mewtwo/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo.json")
+        .create();
+
+    let _m2 = mock("GET", "/translate/yoda")
+        .match_query(Matcher::UrlEncoded("text".into(), "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.".into()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/mewtwo_yoda.json")
+        .create();
+
+    let _m3 = mock("GET", "/api/v2/pokemon-species/ditto/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body_from_file("replays/ditto.json")
+        .create();
+
+    let upstream = UpstreamConfig::default();
+    let app = TestApp {
+        upstream: upstream.clone(),
+        ..TestApp::default()
+    }
+    .init()
+    .await;
+    let admin = test::init_service(App::new().configure(|cfg| crate::admin(cfg, &upstream))).await;
+
+    // faults can only be injected through the admin port
+    let req = test::TestRequest::with_uri("/chaos")
+        .method(Method::PUT)
+        .set_json(&serde_json::json!({}))
+        .to_request();
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let faults = serde_json::json!({
+        "pokeapi": { "resetRate": 0.0 },
+        "funtranslations": { "tooManyRequestsRate": 1.0 },
+    });
+    let req = test::TestRequest::with_uri("/chaos")
+        .method(Method::PUT)
+        .set_json(&faults)
+        .to_request();
+    let resp: ServiceResponse = admin.call(req).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::with_uri("/chaos").to_request();
+    let resp: ServiceResponse = admin.call(req).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::OK);
+    let current: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(current["funtranslations"]["tooManyRequestsRate"], 1.0);
+    assert_eq!(current["pokeapi"]["resetRate"], 0.0);
+
+    // funtranslations answering 429 falls back to the original description, uncached
+    let req = test::TestRequest::with_uri("/pokemon/translated/mewtwo").to_request();
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "no-store");
+    let result: PokemonInfo = test::read_body_json(resp).await;
+    assert_eq!(result.description, "It was created by a scientist after years of horrific gene splicing and DNA engineering experiments.");
+
+    // pokeapi resetting the connection fails the request
+    let req = test::TestRequest::with_uri("/chaos")
+        .method(Method::PUT)
+        .set_json(&serde_json::json!({ "pokeapi": { "resetRate": 1.0 } }))
+        .to_request();
+    let resp: ServiceResponse = admin.call(req).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::with_uri("/pokemon/ditto").to_request();
+    let resp: ServiceResponse = app.call(req).await.expect("valid response");
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+/// A client trusting the test CA, presenting the test certificate `identity` if set
+fn tls_client(identity: Option<&str>) -> reqwest::Client {
+    let ca = fs::read("test-certs/ca.pem").unwrap();
+    let mut client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&ca).unwrap());
+    if let Some(name) = identity {
+        let mut pem = fs::read(format!("test-certs/{}.pem", name)).unwrap();
+        pem.extend(fs::read(format!("test-certs/{}.key", name)).unwrap());
+        client = client.identity(reqwest::Identity::from_pem(&pem).unwrap());
+    }
+    client.build().unwrap()
+}
+
+#[actix_rt::test]
+async fn mutual_tls() {
+    let cert = tls::ReloadingCert::load("test-certs/server.pem".into(), "test-certs/server.key".into())
+        .expect("valid certificate");
+    let config = tls::server_config(std::sync::Arc::new(cert), Some("test-certs/ca.pem".as_ref()))
+        .expect("valid client CA");
+    let server = actix_web::HttpServer::new(|| App::new().route("/", web::get().to(|| async { "ok" })))
+        .workers(1)
+        .disable_signals()
+        .bind_rustls(("127.0.0.1", 0), config)
+        .expect("server binds");
+    let url = format!("https://localhost:{}/", server.addrs()[0].port());
+    let server = server.run();
+    actix_rt::spawn(server.clone().map(drop));
+
+    // clients must present a certificate signed by the client CA
+    for (identity, ok) in [(None, false), (Some("client"), true), (Some("untrusted-client"), false)] {
+        let resp = tls_client(identity).get(&url).send().await;
+        let resp = match resp {
+            Ok(resp) => resp.text().await,
+            Err(err) => Err(err),
+        };
+        assert_eq!(resp.is_ok(), ok, "{:?}: {:?}", identity, resp);
+        if ok {
+            assert_eq!(resp.unwrap(), "ok");
+        }
+    }
+
+    server.stop(true).await;
+}
+
+/// A pokeapi stand-in serving mewtwo over TLS, with the test certificate for localhost
+fn tls_pokeapi() -> (dev::Server, u16) {
+    let cert = tls::ReloadingCert::load("test-certs/server.pem".into(), "test-certs/server.key".into())
+        .expect("valid certificate");
+    let config = tls::server_config(std::sync::Arc::new(cert), None).expect("valid certificate");
+    let species = || async { fs::read_to_string("replays/mewtwo.json").unwrap() };
+    let server = actix_web::HttpServer::new(move || {
+        App::new().route("/api/v2/pokemon-species/mewtwo/", web::get().to(species))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind_rustls(("127.0.0.1", 0), config)
+    .expect("server binds");
+    let port = server.addrs()[0].port();
+    let server = server.run();
+    actix_rt::spawn(server.clone().map(drop));
+    (server, port)
+}
+
+/// A proxy stand-in tunnelling `CONNECT` requests, noting the host and port each asked for
+fn connect_proxy() -> (String, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").expect("proxy binds");
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let targets = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let connects = targets.clone();
+    std::thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            let connects = connects.clone();
+            std::thread::spawn(move || {
+                let mut reader = BufReader::new(client.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let target = match line.strip_prefix("CONNECT ") {
+                    Some(rest) => rest.split(' ').next().unwrap_or_default().to_owned(),
+                    None => return,
+                };
+                // the client sends nothing more until the tunnel is open
+                while reader.read_line(&mut line).unwrap() > 2 {}
+                connects.lock().unwrap().push(target.clone());
+
+                let upstream = TcpStream::connect(&target).unwrap();
+                (&client).write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+                let (mut to_upstream, mut from_client) = (upstream.try_clone().unwrap(), client.try_clone().unwrap());
+                std::thread::spawn(move || {
+                    let _ = std::io::copy(&mut from_client, &mut to_upstream);
+                    let _ = to_upstream.shutdown(Shutdown::Write);
+                });
+                let (mut from_upstream, mut to_client) = (upstream, client);
+                let _ = std::io::copy(&mut from_upstream, &mut to_client);
+                let _ = to_client.shutdown(Shutdown::Write);
+            });
+        }
+    });
+    (url, targets)
+}
+
+#[actix_rt::test]
+async fn upstream_tls_through_proxy() {
+    let (server, port) = tls_pokeapi();
+    let (proxy, targets) = connect_proxy();
+
+    use sha2::{Digest, Sha256};
+
+    let fingerprint = |name: &str| {
+        let pem = fs::read(format!("test-certs/{}.pem", name)).unwrap();
+        let certs = rustls::internal::pemfile::certs(&mut pem.as_slice()).unwrap();
+        format!("localhost={:x}", Sha256::digest(&certs[0].0))
+    };
+    let (ca, pin, wrong_pin) = ("test-certs/ca.pem", fingerprint("server"), fingerprint("rotated"));
+
+    // the test CA must be trusted, and a pinned host must present its pinned certificate
+    let cases = [
+        (vec![ca], vec![], StatusCode::OK),
+        (vec![], vec![], StatusCode::INTERNAL_SERVER_ERROR),
+        (vec![ca], vec![&pin], StatusCode::OK),
+        (vec![ca], vec![&wrong_pin], StatusCode::INTERNAL_SERVER_ERROR),
+    ];
+    for (ca_files, pins, status) in &cases {
+        let egress = Egress::new(EgressConfig {
+            https_proxy: Some(proxy.parse().unwrap()),
+            ca_files: ca_files.iter().map(PathBuf::from).collect(),
+            pins: pins.iter().map(|pin| pin.parse().unwrap()).collect(),
+            ..EgressConfig::default()
+        })
+        .expect("valid egress config");
+        let app = TestApp {
+            config: AppConfig {
+                pokemon_url: format!("https://localhost:{}", port).into(),
+                ..MOCK_CONFIG.clone()
+            },
+            upstream: UpstreamConfig {
+                egress,
+                ..UpstreamConfig::default()
+            },
+            ..TestApp::default()
+        }
+        .init()
+        .await;
+
+        let req = test::TestRequest::with_uri("/pokemon/mewtwo").to_request();
+        let resp: ServiceResponse = app.call(req).await.expect("valid response");
+
+        assert_eq!(resp.status(), *status, "CA files {:?}, pins {:?}", ca_files, pins);
+    }
+
+    // every request was tunnelled through the proxy
+    let target = format!("localhost:{}", port);
+    assert_eq!(*targets.lock().unwrap(), vec![target; cases.len()]);
+
+    server.stop(true).await;
 }
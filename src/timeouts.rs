@@ -0,0 +1,166 @@
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use actix_web::{rt, HttpRequest};
+use reqwest::{Client, Request, Response};
+use reqwest_middleware::{Middleware, Next};
+use task_local_extensions::Extensions;
+
+use crate::{egress::Egress, upstream::Upstream};
+
+/// Timeouts for the calls to an upstream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// To establish the connection
+    pub connect: Duration,
+    /// To start receiving the response once connected
+    pub read: Duration,
+    /// For the whole call, including reading the body and failing over between mirrors
+    pub total: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(2),
+            read: Duration::from_secs(5),
+            total: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The [`Timeouts`] for each upstream
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UpstreamTimeouts {
+    pub pokeapi: Timeouts,
+    pub funtranslations: Timeouts,
+}
+
+/// An upstream call that took longer than its [`Timeouts`] allow
+#[derive(Debug)]
+pub struct TimedOut(Upstream);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out waiting for {} to respond", self.0.name())
+    }
+}
+
+impl Error for TimedOut {}
+
+/// When the call to an upstream has to be finished by, started by its first attempt.
+/// Kept in the call's extensions, which every mirror's attempt shares
+#[derive(Clone, Copy, Debug)]
+struct CallDeadline(Instant);
+
+/// Client middleware applying the [`Timeouts`] of each request's [`Upstream`].
+///
+/// reqwest only supports connect timeouts for a whole client, so this sends requests with
+/// a client per upstream instead of passing them on, and must be the last middleware.
+/// The total timeout starts with the first attempt, so attempts failing over to other
+/// mirrors only get what's left of it.
+/// Requests that aren't tagged with an upstream are passed on
+#[derive(Debug)]
+pub struct SendWithTimeouts {
+    pokeapi: (Client, Timeouts),
+    funtranslations: (Client, Timeouts),
+}
+
+impl SendWithTimeouts {
+    /// Clients are built with the `egress` settings
+    ///
+    /// # Errors:
+    /// If a client can't be built
+    pub fn new(timeouts: &UpstreamTimeouts, egress: &Egress) -> Result<Self, reqwest::Error> {
+        let client = |timeouts: Timeouts| -> Result<_, reqwest::Error> {
+            let client = egress
+                .apply(Client::builder())
+                .connect_timeout(timeouts.connect)
+                .build()?;
+            Ok((client, timeouts))
+        };
+        Ok(SendWithTimeouts {
+            pokeapi: client(timeouts.pokeapi)?,
+            funtranslations: client(timeouts.funtranslations)?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for SendWithTimeouts {
+    async fn handle(
+        &self,
+        mut req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let upstream = match extensions.get::<Upstream>() {
+            Some(upstream) => *upstream,
+            None => return next.run(req, extensions).await,
+        };
+        let (client, timeouts) = match upstream {
+            Upstream::Pokeapi => &self.pokeapi,
+            Upstream::Funtranslations => &self.funtranslations,
+        };
+
+        let deadline = match extensions.get::<CallDeadline>() {
+            Some(CallDeadline(deadline)) => *deadline,
+            None => {
+                let deadline = Instant::now() + timeouts.total;
+                extensions.insert(CallDeadline(deadline));
+                deadline
+            }
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        // reqwest's timeout carries on while the body is read, after this returns
+        *req.timeout_mut() = Some(remaining);
+
+        // the read timeout can't be told apart from connecting, so connecting is allowed for too
+        let read = (timeouts.connect + timeouts.read).min(remaining);
+        match rt::time::timeout(read, client.execute(req)).await {
+            Ok(res) => Ok(res?),
+            Err(_) => Err(reqwest_middleware::Error::middleware(TimedOut(upstream))),
+        }
+    }
+}
+
+/// When the handling of a request should be finished by, after which slow steps like
+/// translating fall back rather than keep waiting. Steps that can't fall back, like fetching
+/// from pokeapi, aren't limited by it
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline(pub Instant);
+
+/// The deadline for the request passed before a step finished
+#[derive(Debug)]
+pub struct DeadlineExceeded;
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("request deadline exceeded")
+    }
+}
+
+impl Error for DeadlineExceeded {}
+
+/// Run `step`, giving up with [`DeadlineExceeded`] if the request's [`Deadline`] passes first
+///
+/// # Errors:
+/// If `step` fails, or the deadline passes
+pub async fn before_deadline<T>(
+    req: &HttpRequest,
+    step: impl Future<Output = Result<T, Box<dyn Error>>>,
+) -> Result<T, Box<dyn Error>> {
+    let deadline = req.extensions().get::<Deadline>().copied();
+    match deadline {
+        Some(Deadline(deadline)) => {
+            rt::time::timeout(deadline.saturating_duration_since(Instant::now()), step)
+                .await
+                .unwrap_or_else(|_| Err(DeadlineExceeded.into()))
+        }
+        None => step.await,
+    }
+}
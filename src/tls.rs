@@ -0,0 +1,270 @@
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{http::header::LOCATION, rt, web, HttpRequest, HttpResponse};
+use rustls::{
+    internal::pemfile, sign::CertifiedKey, AllowAnyAuthenticatedClient, ClientHello, NoClientAuth,
+    ResolvesServerCert, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tracing::{info, warn};
+
+/// How often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The server certificate, reloaded from disk when the files change so certificates can be rotated
+pub struct ReloadingCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<(CertifiedKey, Option<SystemTime>)>,
+}
+
+impl ReloadingCert {
+    /// Load the PEM encoded certificate chain and private key
+    ///
+    /// # Errors:
+    /// If either file can't be read or is invalid
+    pub fn load(cert_path: PathBuf, key_path: PathBuf) -> Result<Self, Box<dyn Error>> {
+        let modified = last_modified(&cert_path, &key_path);
+        let key = read_certified_key(&cert_path, &key_path)?;
+        Ok(ReloadingCert {
+            cert_path,
+            key_path,
+            current: RwLock::new((key, modified)),
+        })
+    }
+
+    /// Reload the certificate if either file has changed since it was last loaded.
+    /// If the new files are invalid, the current certificate is kept
+    ///
+    /// # Errors:
+    /// If either file can't be read or is invalid
+    pub fn reload_if_changed(&self) -> Result<bool, Box<dyn Error>> {
+        let modified = last_modified(&self.cert_path, &self.key_path);
+        if modified == self.current.read().unwrap().1 {
+            return Ok(false);
+        }
+
+        let key = read_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = (key, modified);
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().0.clone())
+    }
+}
+
+/// Create the TLS config serving `cert`.
+/// If `client_ca` is set, clients must present a certificate signed by one of its certificates
+///
+/// # Errors:
+/// If the client CA file can't be read or is invalid
+pub fn server_config(
+    cert: Arc<ReloadingCert>,
+    client_ca: Option<&Path>,
+) -> Result<ServerConfig, Box<dyn Error>> {
+    let verifier = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let (valid, _) = roots
+                .add_pem_file(&mut BufReader::new(File::open(path)?))
+                .map_err(|()| format!("invalid client CA file {:?}", path))?;
+            if valid == 0 {
+                return Err(format!("no certificates in client CA file {:?}", path).into());
+            }
+            AllowAnyAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::new(verifier);
+    config.cert_resolver = cert;
+    Ok(config)
+}
+
+/// Periodically reload the certificate when its files change
+pub async fn reload_on_change(cert: Arc<ReloadingCert>) {
+    loop {
+        rt::time::sleep(RELOAD_INTERVAL).await;
+        match cert.reload_if_changed() {
+            Ok(true) => info!("reloaded TLS certificate"),
+            Ok(false) => {}
+            Err(err) => warn!(%err, "error reloading TLS certificate, keeping the current one"),
+        }
+    }
+}
+
+fn read_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, Box<dyn Error>> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|()| format!("invalid certificate file {:?}", cert_path))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {:?}", cert_path).into());
+    }
+
+    // keys may be either PKCS#8 or PKCS#1 RSA keys
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|()| format!("invalid key file {:?}", key_path))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|()| format!("invalid key file {:?}", key_path))?;
+    }
+    let key = keys
+        .first()
+        .ok_or_else(|| format!("no private key in {:?}", key_path))?;
+    let key = rustls::sign::any_supported_type(key)
+        .map_err(|()| format!("unsupported private key in {:?}", key_path))?;
+
+    let key = CertifiedKey::new(certs, Arc::new(key));
+    if !key_matches_cert(&key) {
+        return Err(format!(
+            "the key in {:?} doesn't match the certificate in {:?}",
+            key_path, cert_path
+        )
+        .into());
+    }
+    Ok(key)
+}
+
+/// Whether the private key belongs to the certificate, by checking a signature made with it
+fn key_matches_cert(key: &CertifiedKey) -> bool {
+    let schemes = [
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::RSA_PSS_SHA256,
+            &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        ),
+        (
+            SignatureScheme::RSA_PKCS1_SHA256,
+            &webpki::RSA_PKCS1_2048_8192_SHA256,
+        ),
+    ];
+    let offered: Vec<_> = schemes.iter().map(|(scheme, _)| *scheme).collect();
+
+    let signer = match key.key.choose_scheme(&offered) {
+        Some(signer) => signer,
+        None => return false,
+    };
+    let algorithm = match schemes
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.get_scheme())
+    {
+        Some((_, algorithm)) => algorithm,
+        None => return false,
+    };
+    let cert = match key.end_entity_cert() {
+        Ok(cert) => cert,
+        Err(()) => return false,
+    };
+
+    let message = b"pokefun certificate check";
+    match (signer.sign(message), webpki::EndEntityCert::from(&cert.0)) {
+        (Ok(signature), Ok(cert)) => cert
+            .verify_signature(algorithm, message, &signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+fn last_modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    let cert = fs::metadata(cert_path).and_then(|m| m.modified()).ok()?;
+    let key = fs::metadata(key_path).and_then(|m| m.modified()).ok()?;
+    Some(cert.max(key))
+}
+
+/// Port HTTPS is served on, for redirecting plain HTTP requests to
+#[derive(Clone, Copy, Debug)]
+pub struct HttpsPort(pub u16);
+
+/// Redirect any request to the same url over HTTPS
+pub async fn redirect(req: HttpRequest, port: web::Data<HttpsPort>) -> HttpResponse {
+    let info = req.connection_info();
+    let host = info.host();
+
+    // drop the plain HTTP port, taking care not to split up IPv6 addresses like `[::1]`
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => host,
+    };
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+
+    let location = match port.0 {
+        443 => format!("https://{}{}", host, path),
+        port => format!("https://{}:{}{}", host, port, path),
+    };
+
+    HttpResponse::PermanentRedirect()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::ReloadingCert;
+
+    /// Copy a test certificate and its key to where the test serves them from
+    fn install(dir: &Path, cert: &str, key: &str) {
+        fs::copy(format!("test-certs/{}.pem", cert), dir.join("cert.pem")).unwrap();
+        fs::copy(format!("test-certs/{}.key", key), dir.join("key.pem")).unwrap();
+    }
+
+    fn serving(cert: &ReloadingCert) -> Vec<u8> {
+        cert.current.read().unwrap().0.cert[0].0.clone()
+    }
+
+    fn pem(name: &str) -> Vec<u8> {
+        let file = fs::read(format!("test-certs/{}.pem", name)).unwrap();
+        rustls::internal::pemfile::certs(&mut &file[..]).unwrap()[0]
+            .0
+            .clone()
+    }
+
+    #[test]
+    fn reload_certificate() {
+        let dir = std::env::temp_dir().join(format!("pokefun-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        install(&dir, "server", "server");
+        let cert = ReloadingCert::load(dir.join("cert.pem"), dir.join("key.pem")).unwrap();
+        assert_eq!(serving(&cert), pem("server"));
+        assert!(!cert.reload_if_changed().unwrap());
+
+        install(&dir, "rotated", "rotated");
+        assert!(cert.reload_if_changed().unwrap());
+        assert_eq!(serving(&cert), pem("rotated"));
+
+        // a key that doesn't match the certificate keeps the current one
+        install(&dir, "server", "rotated");
+        assert!(cert.reload_if_changed().is_err());
+        assert_eq!(serving(&cert), pem("rotated"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mismatched_key() {
+        let err = ReloadingCert::load(
+            "test-certs/server.pem".into(),
+            "test-certs/client.key".into(),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("doesn't match"), "{}", err);
+    }
+}
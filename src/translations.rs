@@ -1,9 +1,20 @@
 use actix_web::{HttpRequest, Result};
+use reqwest::StatusCode;
 use reqwest_middleware::ClientWithMiddleware;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    cache::{self, Entry, Fetched},
+    upstream::Upstream,
+};
+
+/// The funtranslations engines the service translates with
+pub const ENGINES: &[&str] = &["shakespeare", "yoda"];
+
 /// Make a POST request for a fun-translation.
 ///
+/// Translations are cached, and may be served stale, see [`cache::Cache::get_or_fetch`]
+///
 /// # Errors:
 /// Will return [`Err`] if the http connection could not be made,
 /// if the API responded with an error status code
@@ -14,16 +25,42 @@ pub async fn translate(
     translation: &str,
     text: &str,
 ) -> Result<String, Box<dyn std::error::Error>> {
-    Ok(client
-        .get(req.url_for("translations", &[translation])?)
-        .query(&Request { text })
-        .send()
+    let fetch = {
+        let (client, req) = (client.clone(), req.clone());
+        let (translation, text) = (translation.to_owned(), text.to_owned());
+        move |cached| async move { fetch_translation(&client, &req, &translation, &text, cached).await }
+    };
+    let key = format!("{}:{}", translation, text);
+    cache::get_or_fetch(req, |caches| &caches.translations, &key, fetch)
         .await?
-        .error_for_status()?
-        .json::<Response>()
-        .await?
-        .contents
-        .translated)
+        .ok_or_else(|| "translation not found".into())
+}
+
+async fn fetch_translation(
+    client: &ClientWithMiddleware,
+    req: &HttpRequest,
+    translation: &str,
+    text: &str,
+    cached: Option<Entry<String>>,
+) -> Result<Fetched<String>, Box<dyn std::error::Error>> {
+    let mut request = client
+        .get(req.url_for("translations", [translation])?)
+        .query(&Request { text });
+    if let Some(entry) = &cached {
+        request = entry.revalidate(request);
+    }
+
+    let resp = request
+        .send_with_extensions(&mut Upstream::Funtranslations.extensions())
+        .await?;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        return Ok(Fetched::NotModified(resp.headers().clone()));
+    }
+
+    let resp = resp.error_for_status()?;
+    let headers = resp.headers().clone();
+    let translated = resp.json::<Response>().await?.contents.translated;
+    Ok(Fetched::Value(translated, headers))
 }
 
 #[derive(Debug, Serialize)]
@@ -0,0 +1,25 @@
+use task_local_extensions::Extensions;
+
+/// The external APIs this service makes requests to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Upstream {
+    Pokeapi,
+    Funtranslations,
+}
+
+impl Upstream {
+    pub fn name(self) -> &'static str {
+        match self {
+            Upstream::Pokeapi => "pokeapi",
+            Upstream::Funtranslations => "funtranslations",
+        }
+    }
+
+    /// Request extensions tagging the request with this upstream,
+    /// so that the client middleware can tell which API is being called
+    pub fn extensions(self) -> Extensions {
+        let mut extensions = Extensions::new();
+        extensions.insert(self);
+        extensions
+    }
+}
@@ -0,0 +1,51 @@
+#!/bin/sh
+# Generates the certificates used by the TLS tests, valid for 100 years.
+# Run from this directory; the tests only need the resulting files.
+set -e
+
+ec_key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1.key"
+}
+
+# issue <name> <ca> <extensions>, signing <name>.key's certificate with <ca>
+issue() {
+    openssl req -new -key "$1.key" -subj "/CN=$1" -out "$1.csr"
+    printf '%s\n' "$3" > "$1.ext"
+    openssl x509 -req -in "$1.csr" -CA "$2.pem" -CAkey "$2.key" -CAcreateserial \
+        -days 36500 -sha256 -extfile "$1.ext" -out "$1.pem"
+    rm "$1.csr" "$1.ext"
+}
+
+ca() {
+    ec_key "$1"
+    openssl req -x509 -new -key "$1.key" -subj "/CN=$1" -days 36500 -sha256 \
+        -addext "basicConstraints=critical,CA:TRUE" \
+        -addext "keyUsage=critical,keyCertSign,cRLSign" -out "$1.pem"
+}
+
+SERVER='basicConstraints=CA:FALSE
+keyUsage=critical,digitalSignature
+extendedKeyUsage=serverAuth
+subjectAltName=DNS:localhost'
+
+CLIENT='basicConstraints=CA:FALSE
+keyUsage=critical,digitalSignature
+extendedKeyUsage=clientAuth'
+
+# the test CA, and another that isn't trusted
+ca ca
+ca untrusted-ca
+
+# two server certificates, to rotate between
+ec_key server
+issue server ca "$SERVER"
+ec_key rotated
+issue rotated ca "$SERVER"
+
+# client certificates signed by the trusted and untrusted CAs
+ec_key client
+issue client ca "$CLIENT"
+ec_key untrusted-client
+issue untrusted-client untrusted-ca "$CLIENT"
+
+rm -f ./*.srl